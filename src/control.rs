@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+
+/// Command received on the control topic.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Cancel the one-off message(s) to a topic scheduled at a time
+    Remove {
+        topic: String,
+        time: DateTime<FixedOffset>,
+    },
+    /// Reconnect to the broker(s), reloading the credentials
+    Reconnect,
+}
+
+/// Command as it is sent in JSON, e.g.
+/// `{"command": "remove", "topic": "lights/kitchen", "time": "2022-03-28T18:00:00+01:00"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum RawCommand {
+    Remove { topic: String, time: String },
+    Reconnect,
+}
+
+/// Parses a command received on the control topic.
+pub(crate) fn parse_command(payload: &str) -> Result<Command> {
+    match serde_json::from_str(payload)? {
+        RawCommand::Remove { topic, time } => Ok(Command::Remove {
+            topic,
+            time: DateTime::parse_from_rfc3339(&time)
+                .map_err(|e| anyhow!("Invalid time \"{}\": {}", time, e))?,
+        }),
+        RawCommand::Reconnect => Ok(Command::Reconnect),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_command() {
+        assert_eq!(
+            parse_command(
+                r#"{"command": "remove", "topic": "a/b", "time": "2022-03-28T18:00:00+01:00"}"#
+            )
+            .unwrap(),
+            Command::Remove {
+                topic: "a/b".into(),
+                time: DateTime::parse_from_rfc3339("2022-03-28T17:00:00Z").unwrap(),
+            }
+        );
+
+        assert!(parse_command(r#"{"command": "remove", "topic": "a", "time": "18:00"}"#).is_err());
+        assert!(parse_command(r#"{"command": "remove", "topic": "a"}"#).is_err());
+        assert!(parse_command(r#"{"command": "stop"}"#).is_err());
+        assert!(parse_command("remove a").is_err());
+    }
+
+    #[test]
+    fn reconnect_command() {
+        assert_eq!(
+            parse_command(r#"{"command": "reconnect"}"#).unwrap(),
+            Command::Reconnect
+        );
+    }
+}
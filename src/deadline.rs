@@ -0,0 +1,83 @@
+use super::{recv_event, script, Event};
+use chrono::{DateTime, FixedOffset};
+use tokio::{sync::broadcast::Sender, task::JoinHandle};
+
+/// Time at which the actor should exit, the earliest of the given end time and the end of the
+/// given run duration from `start`.
+pub(crate) fn deadline(
+    run_until: Option<DateTime<FixedOffset>>,
+    run_for: Option<chrono::Duration>,
+    start: DateTime<FixedOffset>,
+) -> Option<DateTime<FixedOffset>> {
+    let run_for = run_for.map(|d| start + d);
+    match (run_until, run_for) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Exits the actor once the deadline has passed, regardless of whether any messages are still
+/// scheduled.
+pub(crate) fn run(tx: Sender<Event>, deadline: DateTime<FixedOffset>) -> JoinHandle<()> {
+    let mut rx = tx.subscribe();
+    let remaining = (deadline - script::now()).to_std().unwrap_or_default();
+
+    tokio::spawn(async move {
+        let sleep = tokio::time::sleep(remaining);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                event = recv_event(&mut rx, "Deadline") => {
+                    if matches!(event, Some(Event::Exit) | None) {
+                        log::debug!("Task exit");
+                        return;
+                    }
+                }
+                _ = &mut sleep => {
+                    log::info!("Deadline {} has passed", deadline.to_rfc3339());
+                    if let Err(e) = tx.send(Event::Exit) {
+                        log::error!("Failed to send exit event: {}", e);
+                    }
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn deadline_earliest() {
+        let start = DateTime::parse_from_rfc3339("2022-03-28T10:00:00+00:00").unwrap();
+        let until = DateTime::parse_from_rfc3339("2022-03-28T12:00:00+00:00").unwrap();
+
+        assert_eq!(deadline(None, None, start), None);
+        assert_eq!(deadline(Some(until), None, start), Some(until));
+        assert_eq!(
+            deadline(None, Some(chrono::Duration::hours(1)), start),
+            Some(start + chrono::Duration::hours(1))
+        );
+        assert_eq!(
+            deadline(Some(until), Some(chrono::Duration::hours(1)), start),
+            Some(start + chrono::Duration::hours(1))
+        );
+        assert_eq!(
+            deadline(Some(until), Some(chrono::Duration::hours(3)), start),
+            Some(until)
+        );
+    }
+
+    #[tokio::test]
+    async fn exit_after_deadline() {
+        let (tx, mut rx) = broadcast::channel::<Event>(4);
+
+        let task = run(tx, script::now() - chrono::Duration::seconds(1));
+        assert_eq!(recv_event(&mut rx, "Test").await, Some(Event::Exit));
+        task.await.unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use super::Event;
+use super::{script, Cli, Event};
 use anyhow::Result;
 use notify::{
     self,
@@ -8,18 +8,45 @@ use notify::{
 use std::path::Path;
 use tokio::sync::broadcast::Sender;
 
-pub(crate) fn run(tx: Sender<Event>, path: &Path) -> Result<RecommendedWatcher> {
+/// Watches the script source directory, unless watching is disabled.
+pub(crate) fn run_if_enabled(tx: Sender<Event>, args: &Cli) -> Result<Option<RecommendedWatcher>> {
+    if args.no_watch {
+        log::info!("Not watching for script changes");
+        Ok(None)
+    } else if script::script_url(args.script_source_dir()).is_some() {
+        log::debug!("Not watching a script that is fetched from a URL");
+        Ok(None)
+    } else {
+        run(
+            tx,
+            args.script_source_dir(),
+            &args.script_glob,
+            &args.ignore_pattern,
+        )
+        .map(Some)
+    }
+}
+
+pub(crate) fn run(
+    tx: Sender<Event>,
+    path: &Path,
+    globs: &[String],
+    ignore: &[String],
+) -> Result<RecommendedWatcher> {
+    // Events are reported for paths under the path that is watched, an absolute path ensures they
+    // can be matched against the glob patterns relative to it
+    let path = path.canonicalize()?;
+    let dir = path.clone();
+    let globs = globs.to_vec();
+    let ignore = ignore.to_vec();
+
     let mut watcher =
         notify::recommended_watcher(move |event: std::result::Result<event::Event, Error>| {
             if let Ok(event) = event {
                 if event
                     .paths
                     .iter()
-                    .map(|p| match p.extension() {
-                        Some(ext) => ext == "txt",
-                        None => false,
-                    })
-                    .any(|m| m)
+                    .any(|p| script::is_script_file(&dir, &globs, &ignore, p))
                     && matches!(
                         event.kind,
                         EventKind::Create(_)
@@ -39,7 +66,25 @@ pub(crate) fn run(tx: Sender<Event>, path: &Path) -> Result<RecommendedWatcher>
             }
         })?;
 
-    watcher.watch(path, RecursiveMode::Recursive)?;
+    watcher.watch(&path, RecursiveMode::Recursive)?;
 
     Ok(watcher)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn no_watch() {
+        let (tx, _) = broadcast::channel(16);
+
+        let args = Cli::parse_from(["mqtt-actor", "--no-watch", "."]);
+        assert!(run_if_enabled(tx.clone(), &args).unwrap().is_none());
+
+        let args = Cli::parse_from(["mqtt-actor", "."]);
+        assert!(run_if_enabled(tx, &args).unwrap().is_some());
+    }
+}
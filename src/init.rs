@@ -0,0 +1,121 @@
+use crate::script;
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path};
+
+/// Name of the example script file written to the script directory.
+const EXAMPLE_FILE_NAME: &str = "example.txt";
+
+/// Example script, explaining the columns and the main forms of timestamp.
+const EXAMPLE_SCRIPT: &str = r##"# Example mqtt-actor script, see the README for every option.
+#
+# Each line is a message: [timestamp] | [topic] | [message]
+# optionally followed by | [retain] | [expiry] | [priority] | [valid_for]
+# Lines starting with "#" are comments and blank lines are ignored.
+
+# Relative timestamps are seconds (or suffixed with "s", "m", "h" or "d") after the previous
+# message in this file, or after the file was loaded for the first message.
+0  | example/relative | Sent as soon as the file is loaded
+5  | example/relative | Five seconds after the message above
+1m | example/relative | One minute after the message above
+
+# The retain flag makes the broker keep the last message on a topic for new subscribers.
+0 | example/retained | Retained message | true
+
+# Repeating messages, here every 30 seconds (from the previous message), at most 10 times.
+every:30s:10 | example/repeating | Ping
+
+# Daily messages, here every day at 08:00 local time.
+midnight+8h | example/daily | Good morning!
+
+# Absolute timestamps, in RFC3339 or RFC2822 format, are sent at that time.
+2030-01-01T09:00:00+00:00 | example/absolute | Happy new year!
+"##;
+
+/// Writes an example script to the directory, creating the directory if needed.
+/// An existing example script is only overwritten if forced.
+pub(crate) fn run(dir: &Path, force: bool) -> Result<()> {
+    if script::script_url(dir).is_some() {
+        return Err(anyhow!(
+            "An example script can only be written to a directory"
+        ));
+    }
+
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(EXAMPLE_FILE_NAME);
+    if path.exists() && !force {
+        return Err(anyhow!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        ));
+    }
+
+    fs::write(&path, EXAMPLE_SCRIPT)?;
+    println!("Wrote example script to {}", path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        script::{Script, ScriptOptions},
+        Cli, Command,
+    };
+    use clap::Parser;
+
+    #[test]
+    fn example_script_parses() {
+        let dir = tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap();
+        run(dir.path(), false).unwrap();
+
+        let options = ScriptOptions {
+            strict_start: true,
+            ..Default::default()
+        };
+        let script = Script::new(dir.path(), options).unwrap();
+        assert_eq!(
+            script.file_message_counts(),
+            vec![(dir.path().join(EXAMPLE_FILE_NAME).as_path(), 7)]
+        );
+    }
+
+    #[test]
+    fn refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(EXAMPLE_FILE_NAME);
+        fs::write(&path, "0 | my/topic | mine").unwrap();
+
+        assert!(run(dir.path(), false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0 | my/topic | mine");
+
+        run(dir.path(), true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), EXAMPLE_SCRIPT);
+    }
+
+    #[test]
+    fn init_subcommand() {
+        let args = Cli::parse_from(["mqtt-actor", "init", "--force", "scripts"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Init { ref dir, force: true }) if dir == Path::new("scripts")
+        ));
+        assert!(args.script_source_dir.is_none());
+
+        let args = Cli::parse_from(["mqtt-actor", "scripts"]);
+        assert!(args.command.is_none());
+        assert_eq!(args.script_source_dir(), Path::new("scripts"));
+
+        assert!(Cli::try_parse_from(["mqtt-actor"]).is_err());
+        assert!(Cli::try_parse_from(["mqtt-actor", "--list", "init", "scripts"]).is_err());
+    }
+
+    #[test]
+    fn refuses_url() {
+        assert!(run(Path::new("https://example.com/script.txt"), false).is_err());
+    }
+}
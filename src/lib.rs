@@ -0,0 +1,709 @@
+//! Scheduling engine of mqtt-actor, which publishes MQTT messages according to a script.
+//!
+//! The [`script`] module parses script files and determines which messages are due to be sent,
+//! [`processing`] drives a [`script::Script`] from [`Event`]s on a broadcast channel, emitting
+//! [`Event::SendMessage`] for each message that is due, and [`run`] runs the whole actor.
+
+mod control;
+mod deadline;
+mod file_watch;
+mod init;
+mod list;
+mod metrics;
+mod mqtt;
+pub mod processing;
+mod record;
+mod reload;
+pub mod script;
+mod signals;
+mod status;
+mod tick;
+mod warnings;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+pub use mqtt::ConnectionState;
+use script::Message;
+use std::path::{Path, PathBuf};
+use tokio::{
+    signal,
+    sync::broadcast::{self, error::RecvError, Receiver},
+};
+
+/// A simple tool to schedule MQTT messages
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Log more, may be repeated (debug, then trace). RUST_LOG takes precedence if it is set.
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less, may be repeated (warnings, errors, then nothing). RUST_LOG takes precedence if it
+    /// is set.
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Address of MQTT broker to connect to, "tcp://", "ssl://", "ws://" and "wss://" schemes are
+    /// supported. May be given multiple times to publish to several brokers.
+    #[clap(
+        long,
+        env = "MQTT_BROKER",
+        value_delimiter = ',',
+        default_value = "tcp://localhost:1883"
+    )]
+    mqtt_broker: Vec<String>,
+
+    /// Client ID to use when connecting to MQTT broker
+    #[clap(long, env = "MQTT_CLIENT_ID", default_value = "mqtt-actor")]
+    mqtt_client_id: String,
+
+    /// Append the hostname, process ID and/or index of the broker (in the order given, starting at
+    /// 0) to the client ID, separated by "-", so that several instances (or the connections to
+    /// several brokers) do not use the same client ID
+    #[clap(long, env = "MQTT_CLIENT_ID_SUFFIX", value_delimiter = ',', value_enum)]
+    mqtt_client_id_suffix: Vec<mqtt::ClientIdSuffix>,
+
+    /// Client ID ("broker=client_id") to use when connecting to one of the brokers, instead of the
+    /// (suffixed) client ID. May be given multiple times.
+    #[clap(
+        long = "mqtt-broker-client-id",
+        env = "MQTT_BROKER_CLIENT_IDS",
+        value_delimiter = ',',
+        value_parser = mqtt::parse_broker_client_id
+    )]
+    mqtt_broker_client_ids: Vec<(String, String)>,
+
+    /// MQTT protocol version, must be 3 (v3.1), 4 (v3.1.1) or 5, negotiated if not set
+    #[clap(long, env = "MQTT_VERSION", value_parser = clap::value_parser!(u32).range(3..=5))]
+    mqtt_version: Option<u32>,
+
+    /// Start a clean session (clean start in MQTT v5) on connect.
+    ///
+    /// Set to false with a stable client ID to resume a persistent session, in which the broker
+    /// keeps subscriptions and QoS 1 and 2 messages that are not yet acknowledged. Messages that
+    /// are in flight when the process exits are not persisted locally and may be lost.
+    #[clap(
+        long,
+        env = "MQTT_CLEAN_SESSION",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    mqtt_clean_session: bool,
+
+    /// MQTT QoS, must be 0, 1 or 2
+    #[clap(
+        long,
+        env = "MQTT_QOS",
+        default_value = "0",
+        value_parser = clap::value_parser!(i32).range(0..=2)
+    )]
+    mqtt_qos: i32,
+
+    /// MQTT username
+    #[clap(long, env = "MQTT_USERNAME", default_value = "")]
+    mqtt_username: String,
+
+    /// MQTT password
+    #[clap(long, env = "MQTT_PASSWORD", default_value = "")]
+    mqtt_password: String,
+
+    /// File to read the MQTT username from, takes precedence over the username option
+    #[clap(long, env = "MQTT_USERNAME_FILE")]
+    mqtt_username_file: Option<PathBuf>,
+
+    /// File to read the MQTT password from, takes precedence over the password option
+    #[clap(long, env = "MQTT_PASSWORD_FILE")]
+    mqtt_password_file: Option<PathBuf>,
+
+    /// Additional HTTP header ("Name: Value") to send in the WebSocket handshake, only used with
+    /// "ws://" and "wss://" brokers
+    #[clap(
+        long = "mqtt-ws-header",
+        env = "MQTT_WS_HEADERS",
+        value_delimiter = ',',
+        value_parser = mqtt::parse_ws_header
+    )]
+    mqtt_ws_headers: Vec<(String, String)>,
+
+    /// Topic of the last will message, which the broker publishes if the connection to it is lost
+    /// without disconnecting. No last will is set if not given.
+    #[clap(long, env = "MQTT_LWT_TOPIC")]
+    mqtt_lwt_topic: Option<String>,
+
+    /// Payload of the last will message
+    #[clap(long, env = "MQTT_LWT_PAYLOAD", default_value = "offline")]
+    mqtt_lwt_payload: String,
+
+    /// Retain the last will message, so that clients connecting later also see it
+    #[clap(long, env = "MQTT_LWT_RETAIN")]
+    mqtt_lwt_retain: bool,
+
+    /// User property ("name=value") of the last will message. May be given multiple times.
+    /// Requires MQTT v5.
+    #[clap(
+        long = "mqtt-lwt-property",
+        env = "MQTT_LWT_PROPERTIES",
+        value_delimiter = ',',
+        value_parser = mqtt::parse_user_property
+    )]
+    mqtt_lwt_properties: Vec<(String, String)>,
+
+    /// Maximum time to wait for a message to be acknowledged by the broker, in seconds or
+    /// suffixed with "s", "m", "h" or "d". Waits indefinitely if not set.
+    #[clap(long, env = "PUBLISH_TIMEOUT", value_parser = parse_std_duration)]
+    publish_timeout: Option<std::time::Duration>,
+
+    /// Maximum number of messages that are published to each broker without their delivery being
+    /// confirmed yet. With more than one, messages with a QoS above 0 may be delivered out of
+    /// order.
+    #[clap(long, env = "MAX_INFLIGHT", default_value_t = 1)]
+    max_inflight: u32,
+
+    /// Maximum number of MQTT v5 topic aliases to use with each broker, to save sending the full
+    /// topic of every message. Limited to the maximum the broker allows, disabled when 0.
+    #[clap(long, env = "TOPIC_ALIAS_MAX", default_value_t = 0)]
+    topic_alias_max: u16,
+
+    /// Number of events that can be queued for each task before the oldest are dropped
+    #[clap(long, env = "EVENT_BUFFER", default_value_t = 16)]
+    event_buffer: usize,
+
+    /// Topic filter to subscribe to, logging every message received from the broker(s), for
+    /// debugging
+    #[clap(long, env = "DEBUG_SUBSCRIBE")]
+    debug_subscribe: Option<String>,
+
+    /// Topic to receive commands on, as JSON, e.g. to cancel a scheduled message
+    #[clap(long, env = "CONTROL_TOPIC")]
+    control_topic: Option<String>,
+
+    /// Do not connect to any broker, messages are only logged (and recorded with --record-to)
+    #[clap(long, env = "DRY_RUN")]
+    dry_run: bool,
+
+    /// File to append every message that is sent to, as a line of JSON with the time it was sent
+    #[clap(long, env = "RECORD_TO")]
+    record_to: Option<PathBuf>,
+
+    /// Number of times to retry sending a message that failed to send
+    #[clap(long, env = "MAX_RETRIES", default_value_t = 0)]
+    max_retries: u32,
+
+    /// Topic to publish messages that could not be sent to, along with the error, as JSON
+    #[clap(long, env = "DEAD_LETTER_TOPIC")]
+    dead_letter_topic: Option<String>,
+
+    /// Topic to publish a message to with QoS 1 after first connecting to each broker, failing
+    /// to use the broker if it is not acknowledged (e.g. because publishing is not authorised)
+    #[clap(long, env = "WARMUP_TOPIC")]
+    warmup_topic: Option<String>,
+
+    /// Message expiry interval used for messages that do not set one, in seconds or suffixed
+    /// with "s", "m", "h" or "d". Requires MQTT v5.
+    #[clap(long, env = "MESSAGE_EXPIRY", value_parser = parse_std_duration)]
+    message_expiry: Option<std::time::Duration>,
+
+    /// MQTT content type of the payload (e.g. "application/json") used for messages that do not
+    /// set one. Requires MQTT v5.
+    #[clap(long, env = "CONTENT_TYPE")]
+    content_type: Option<String>,
+
+    /// MQTT response topic used for messages that do not set one. Requires MQTT v5.
+    #[clap(long, env = "RESPONSE_TOPIC")]
+    response_topic: Option<String>,
+
+    /// Topic to periodically publish the status of the actor to, as JSON
+    #[clap(long, env = "STATUS_TOPIC")]
+    status_topic: Option<String>,
+
+    /// Number of topic levels that published messages are counted by in the status, e.g. 2 counts
+    /// messages to "home/kitchen/light" as "home/kitchen". All levels are used by default.
+    #[clap(long, env = "METRICS_TOPIC_DEPTH")]
+    metrics_topic_depth: Option<usize>,
+
+    /// Interval between status messages, in seconds or suffixed with "s", "m", "h" or "d"
+    #[clap(long, env = "STATUS_INTERVAL", default_value = "60", value_parser = parse_std_duration)]
+    status_interval: std::time::Duration,
+
+    /// Script file delimiter
+    #[clap(long, env = "SCRIPT_DELIMITER", default_value_t = b'|')]
+    script_delimiter: u8,
+
+    /// Time to wait after first connecting to a broker before sending any messages, e.g. so that
+    /// consumers can subscribe first, in seconds or suffixed with "s", "m", "h" or "d"
+    #[clap(long, env = "STARTUP_DELAY", default_value = "0", value_parser = parse_std_duration)]
+    startup_delay: std::time::Duration,
+
+    /// Bounds on the payload size of messages to topics starting with a prefix
+    /// ("prefix=min..max", in bytes, either bound may be omitted). Messages outside the bounds
+    /// of the longest matching prefix are skipped. May be given multiple times.
+    #[clap(
+        long = "payload-size-limit",
+        env = "PAYLOAD_SIZE_LIMITS",
+        value_delimiter = ',',
+        value_parser = processing::parse_payload_size_limit
+    )]
+    payload_size_limits: Vec<processing::PayloadSizeLimit>,
+
+    /// Bytes to add before the payload of every message, decoded like script payloads
+    /// (e.g. "hex:02" for a raw byte)
+    #[clap(long, env = "PAYLOAD_PREFIX")]
+    payload_prefix: Option<script::Payload>,
+
+    /// Bytes to add after the payload of every message, decoded like script payloads
+    #[clap(long, env = "PAYLOAD_SUFFIX")]
+    payload_suffix: Option<script::Payload>,
+
+    /// Wrap the payload of every message in a JSON object as {"value": <payload>}, before any
+    /// prefix or suffix is added. Payloads that are not JSON are wrapped as strings.
+    #[clap(long, env = "PAYLOAD_WRAP_JSON")]
+    payload_wrap_json: bool,
+
+    /// Maximum number of messages to send per second, unlimited if not set
+    #[clap(long, env = "MAX_RATE")]
+    max_rate: Option<f64>,
+
+    /// Order in which messages that are due at the same time are sent, by priority and timestamp
+    /// (timestamp) or interleaved by topic (round-robin)
+    #[clap(
+        long,
+        env = "PUBLISH_ORDER",
+        value_enum,
+        default_value_t = processing::PublishOrder::Timestamp
+    )]
+    publish_order: processing::PublishOrder,
+
+    /// Template for the value of "{{path}}" in topics, "{dir}" is replaced with the directory of
+    /// the script file relative to the script directory and "{name}" with its name
+    #[clap(long, env = "PATH_TEMPLATE", default_value = script::DEFAULT_PATH_TEMPLATE)]
+    path_template: String,
+
+    /// Glob pattern matching script files, relative to the script directory. May be given
+    /// multiple times.
+    #[clap(
+        long,
+        env = "SCRIPT_GLOB",
+        value_delimiter = ',',
+        default_values = script::DEFAULT_SCRIPT_GLOBS,
+        value_parser = script::parse_script_glob
+    )]
+    script_glob: Vec<String>,
+
+    /// Maximum number of script files to load, in order of path, as a safeguard against loading
+    /// a huge directory by mistake. Unlimited if not set.
+    #[clap(long, env = "MAX_FILES")]
+    max_files: Option<usize>,
+
+    /// Time a script file must not have been modified for before it is loaded, so that files that
+    /// are still being written are not loaded, in seconds or suffixed with "s", "m", "h" or "d"
+    #[clap(long, env = "SETTLE_TIME", default_value = "1", value_parser = parse_std_duration)]
+    settle_time: std::time::Duration,
+
+    /// Glob patterns matching the names of files that are never loaded as script files, even if
+    /// they match a script file glob pattern, e.g. editor temporary and backup files. Files
+    /// starting with "." are always ignored.
+    #[clap(
+        long,
+        env = "IGNORE_PATTERN",
+        value_delimiter = ',',
+        default_values = script::DEFAULT_IGNORE_PATTERNS,
+        value_parser = script::parse_script_glob
+    )]
+    ignore_pattern: Vec<String>,
+
+    /// Additional strptime style formats for absolute timestamps in script files (e.g.
+    /// "%Y-%m-%d %H:%M:%S%z"), separated by ";". Tried in order before the built in formats,
+    /// formats without a timezone offset are interpreted in the configured timezone.
+    #[clap(long, env = "TIMESTAMP_FORMATS", value_delimiter = ';')]
+    timestamp_formats: Vec<String>,
+
+    /// Timezone of absolute timestamps without an offset in script files, daily messages and
+    /// looping scripts, as an IANA name (e.g. "Europe/London") or "local" for the local timezone
+    #[clap(long, env = "TIMEZONE", default_value = "local", value_parser = script::parse_zone)]
+    timezone: script::Zone,
+
+    /// Character that quotes fields in script files, so that they can contain the delimiter or
+    /// leading and trailing whitespace. Must be ASCII.
+    #[clap(long, env = "QUOTE_CHAR", default_value_t = '"')]
+    quote_char: char,
+
+    /// Do not treat any character as a quote in script files
+    #[clap(long, env = "NO_QUOTING")]
+    no_quoting: bool,
+
+    /// Also remove leading and trailing whitespace inside quoted fields of script files
+    #[clap(long, env = "TRIM_QUOTED")]
+    trim_quoted: bool,
+
+    /// Topic of messages in script files that are given without one, i.e. rows with only a
+    /// timestamp and a message
+    #[clap(long, env = "DEFAULT_TOPIC")]
+    default_topic: Option<String>,
+
+    /// Separator between several topics in the topic column of a script file, the message is
+    /// sent to each of them
+    #[clap(long, env = "TOPIC_SEPARATOR", default_value_t = script::DEFAULT_TOPIC_SEPARATOR)]
+    topic_separator: char,
+
+    /// Topic alias ("name=prefix"), topics of the form "@name/..." in script files are expanded to
+    /// start with the prefix
+    #[clap(
+        long = "topic-alias",
+        env = "TOPIC_ALIASES",
+        value_delimiter = ',',
+        value_parser = script::parse_topic_alias
+    )]
+    topic_aliases: Vec<(String, String)>,
+
+    /// Log every message loaded from the script files at info level, rather than only their number
+    /// (every message is logged at debug level)
+    #[clap(long, env = "LOG_LOADED_MESSAGES")]
+    log_loaded_messages: bool,
+
+    /// Log a warning for each loaded message that is already in the past
+    #[clap(long, env = "WARN_PAST")]
+    warn_past: bool,
+
+    /// Refuse to load scripts containing messages with the same topic and time but different
+    /// payloads, instead of only logging a warning
+    #[clap(long, env = "STRICT")]
+    strict: bool,
+
+    /// Fail to start if the script contains no messages, or any file or message that could not be
+    /// loaded
+    #[clap(long, env = "STRICT_START")]
+    strict_start: bool,
+
+    /// Skip messages that reference environment variables ("${VAR}") that are not set, instead of
+    /// substituting an empty string
+    #[clap(long, env = "STRICT_ENV")]
+    strict_env: bool,
+
+    /// File used to persist the last poll time across restarts
+    #[clap(long, env = "STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Exit once all messages have been sent
+    #[clap(long, env = "ONCE")]
+    once: bool,
+
+    /// Topic to publish a message to once all messages have been sent, before exiting
+    #[clap(long, env = "COMPLETE_TOPIC", requires = "once")]
+    complete_topic: Option<String>,
+
+    /// Payload of the message published to the complete topic
+    #[clap(long, env = "COMPLETE_PAYLOAD", default_value = "complete")]
+    complete_payload: String,
+
+    /// What relative timestamps in script files are offset from, the previous message in the file
+    /// (cumulative) or the time the file was loaded (independent)
+    #[clap(
+        long,
+        env = "RELATIVE_MODE",
+        value_enum,
+        default_value_t = script::RelativeMode::Cumulative
+    )]
+    relative_mode: script::RelativeMode,
+
+    /// Replay the script every period, with relative timestamps anchored to the start of each
+    /// period instead of the time the script was loaded
+    #[clap(long = "loop", env = "LOOP", value_enum)]
+    loop_period: Option<script::LoopPeriod>,
+
+    /// Exit when the script becomes empty after a reload, e.g. because every script file was
+    /// removed
+    #[clap(long, env = "EXIT_ON_EMPTY")]
+    exit_on_empty: bool,
+
+    /// Time that countdown timestamps ("deadline-[offset]") in script files are relative to, in
+    /// RFC3339 format
+    #[clap(long, env = "DEADLINE", value_parser = chrono::DateTime::parse_from_rfc3339)]
+    deadline: Option<chrono::DateTime<chrono::FixedOffset>>,
+
+    /// Exit at this time, in RFC3339 format, even if messages are still scheduled
+    #[clap(long, env = "RUN_UNTIL", value_parser = chrono::DateTime::parse_from_rfc3339)]
+    run_until: Option<chrono::DateTime<chrono::FixedOffset>>,
+
+    /// Exit after running for this long, in seconds or suffixed with "s", "m", "h" or "d", even
+    /// if messages are still scheduled
+    #[clap(long, env = "RUN_FOR", value_parser = script::parse_duration)]
+    run_for: Option<chrono::Duration>,
+
+    /// Do not watch the script directory for changes, the script is only loaded at startup
+    #[clap(long, env = "NO_WATCH")]
+    no_watch: bool,
+
+    /// Also check the script files for changes at this interval and reload the script if they
+    /// have changed, in seconds or suffixed with "s", "m", "h" or "d". For filesystems on which
+    /// changes are not reliably detected by watching, e.g. network filesystems.
+    #[clap(long, env = "RELOAD_INTERVAL", value_parser = parse_std_duration)]
+    reload_interval: Option<std::time::Duration>,
+
+    /// Poll the script at this interval, aligned to wall-clock (UTC) boundaries (e.g. "1m" polls
+    /// at the start of every minute), in seconds or suffixed with "s", "m", "h" or "d". By
+    /// default the script is polled every second, counted from startup.
+    #[clap(long, env = "TICK_ALIGN", value_parser = parse_std_duration)]
+    tick_align: Option<std::time::Duration>,
+
+    /// Print the messages due to be sent within the list horizon and exit
+    #[clap(long)]
+    list: bool,
+
+    /// Print the messages due to be sent within the list horizon as JSON and exit
+    #[clap(long, conflicts_with = "list")]
+    dump_json: bool,
+
+    /// How far ahead to list messages, in seconds or suffixed with "s", "m", "h" or "d"
+    #[clap(long, default_value = "24h", value_parser = script::parse_duration)]
+    list_horizon: chrono::Duration,
+
+    /// Directory to watch for script files, or an "http://" or "https://" URL to fetch a script
+    /// from every reload interval (60 seconds by default)
+    #[clap(required = true)]
+    script_source_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Write an example script file to a directory and exit
+    Init {
+        /// Directory to write the example script file to, created if it does not exist
+        dir: PathBuf,
+
+        /// Overwrite the example script file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+/// Maps the number of verbose and quiet flags to a log level, relative to the default of info.
+fn log_level(verbose: u8, quiet: u8) -> log::LevelFilter {
+    match 3 + i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+impl Cli {
+    /// Log level selected by the verbose and quiet flags, used unless RUST_LOG is set.
+    pub fn log_level(&self) -> log::LevelFilter {
+        log_level(self.verbose, self.quiet)
+    }
+
+    /// Script directory or URL, which clap requires unless a subcommand is given.
+    fn script_source_dir(&self) -> &Path {
+        self.script_source_dir
+            .as_deref()
+            .expect("script source is required without a subcommand")
+    }
+}
+
+fn parse_std_duration(s: &str) -> Result<std::time::Duration> {
+    Ok(script::parse_duration(s)?.to_std()?)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Poll the script for messages that are due
+    Tick,
+    /// Reload the script files that have changed
+    ReloadScript,
+    /// Reconnect to the broker(s)
+    Reconnect,
+    /// A connection to the broker was established
+    Connected(String),
+    /// Command received on the control topic
+    Control(control::Command),
+    /// Publish a message, to the broker with the URI if one is given, otherwise to every broker
+    SendMessage(Box<Message>, Option<String>),
+    /// Stop every task
+    Exit,
+}
+
+/// Receives the next event, skipping over (and logging) any events that were dropped because the
+/// task fell behind.
+/// Returns None once all senders have been dropped, which is logged as tasks should stop on an
+/// exit event before that happens.
+pub async fn recv_event(rx: &mut Receiver<Event>, task: &str) -> Option<Event> {
+    loop {
+        match rx.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(n)) => {
+                log::warn!("{} task fell behind, {} events were dropped", task, n);
+            }
+            Err(RecvError::Closed) => {
+                log::warn!(
+                    "{} task stopping, the event channel closed without an exit",
+                    task
+                );
+                return None;
+            }
+        }
+    }
+}
+
+/// Runs the actor with the given options until it exits.
+pub async fn run(args: Cli) -> Result<()> {
+    if let Some(Command::Init { dir, force }) = &args.command {
+        return init::run(dir, *force);
+    }
+
+    let script_url = script::script_url(args.script_source_dir());
+
+    if script_url.is_none() && !args.script_source_dir().is_dir() {
+        return Err(anyhow!(
+            "Path \"{}\" is not an accessible directory",
+            args.script_source_dir().display()
+        ));
+    }
+
+    if args.list || args.dump_json {
+        return list::run(&args);
+    }
+
+    if let Some(rate) = args.max_rate {
+        if rate <= 0.0 {
+            return Err(anyhow!("Maximum rate must be greater than zero"));
+        }
+    }
+
+    if args.message_expiry.is_some() && args.mqtt_version != Some(5) {
+        log::warn!("Message expiry requires MQTT v5 (--mqtt-version 5) and will be ignored");
+    }
+
+    if !args.quote_char.is_ascii() {
+        return Err(anyhow!("Quote character must be ASCII"));
+    }
+
+    if args.status_interval.is_zero() {
+        return Err(anyhow!("Status interval must be greater than zero"));
+    }
+
+    if args
+        .reload_interval
+        .is_some_and(|interval| interval.is_zero())
+    {
+        return Err(anyhow!("Reload interval must be greater than zero"));
+    }
+
+    if args.tick_align.is_some_and(|interval| interval.is_zero()) {
+        return Err(anyhow!("Tick alignment must be greater than zero"));
+    }
+
+    if args.max_inflight == 0 {
+        return Err(anyhow!(
+            "Maximum in flight messages must be greater than zero"
+        ));
+    }
+
+    if args.event_buffer == 0 {
+        return Err(anyhow!("Event buffer must not be empty"));
+    }
+
+    if args.metrics_topic_depth == Some(0) {
+        return Err(anyhow!("Metrics topic depth must be greater than zero"));
+    }
+
+    metrics::set_topic_depth(args.metrics_topic_depth);
+
+    let (tx, mut rx) = broadcast::channel::<Event>(args.event_buffer);
+
+    let _file_watcher = file_watch::run_if_enabled(tx.clone(), &args)?;
+
+    let mut tasks = vec![
+        tick::run(tx.clone(), args.tick_align),
+        signals::run(tx.clone())?,
+    ];
+    let reload_interval = match script_url {
+        Some(_) => Some(args.reload_interval.unwrap_or(reload::URL_RELOAD_INTERVAL)),
+        None => args.reload_interval,
+    };
+    if let Some(interval) = reload_interval {
+        tasks.push(reload::run(
+            tx.clone(),
+            args.script_source_dir(),
+            &args.script_glob,
+            &args.ignore_pattern,
+            interval,
+        ));
+    }
+    if let Some(deadline) = deadline::deadline(args.run_until, args.run_for, script::now()) {
+        tasks.push(deadline::run(tx.clone(), deadline));
+    }
+    if let Some(path) = &args.record_to {
+        tasks.push(record::run(tx.clone(), path, args.mqtt_qos)?);
+    }
+    let brokers = if args.dry_run {
+        log::info!("Dry run, not connecting to any broker");
+        Vec::new()
+    } else {
+        mqtt::run(tx.clone(), &args)?
+    };
+    let connections = brokers
+        .iter()
+        .map(|b| (b.uri.clone(), b.state.clone()))
+        .collect();
+    tasks.extend(brokers.into_iter().map(|b| b.task));
+    tasks.push(processing::run(tx.clone(), &args, connections)?);
+
+    loop {
+        let should_exit = tokio::select!(
+            _ = signal::ctrl_c() => true,
+            event = recv_event(&mut rx, "Main") => matches!(event, Some(Event::Exit) | None),
+        );
+        if should_exit {
+            break;
+        }
+    }
+
+    log::info!("Terminating...");
+    tx.send(Event::Exit)?;
+    for handle in tasks {
+        if let Err(e) = handle.await {
+            log::error!("Failed waiting for task to finish: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_log_level() {
+        assert_eq!(log_level(0, 0), log::LevelFilter::Info);
+        assert_eq!(log_level(1, 0), log::LevelFilter::Debug);
+        assert_eq!(log_level(2, 0), log::LevelFilter::Trace);
+        assert_eq!(log_level(5, 0), log::LevelFilter::Trace);
+        assert_eq!(log_level(0, 1), log::LevelFilter::Warn);
+        assert_eq!(log_level(0, 2), log::LevelFilter::Error);
+        assert_eq!(log_level(0, 3), log::LevelFilter::Off);
+        assert_eq!(log_level(0, 10), log::LevelFilter::Off);
+    }
+
+    #[tokio::test]
+    async fn recv_event_lagged() {
+        let (tx, mut rx) = broadcast::channel::<Event>(2);
+
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::ReloadScript).unwrap();
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::Exit).unwrap();
+
+        assert_eq!(recv_event(&mut rx, "Test").await, Some(Event::Tick));
+        assert_eq!(recv_event(&mut rx, "Test").await, Some(Event::Exit));
+
+        drop(tx);
+        assert_eq!(recv_event(&mut rx, "Test").await, None);
+    }
+}
@@ -0,0 +1,188 @@
+use super::{
+    processing,
+    script::{Message, Script, ScriptOptions},
+    Cli,
+};
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use serde::Serialize;
+
+/// Maximum number of occurrences of each repeating message to list.
+const MAX_OCCURRENCES: usize = 5;
+
+/// Maximum number of characters of a payload to list.
+const PAYLOAD_PREVIEW_LENGTH: usize = 40;
+
+fn payload_preview(payload: &str) -> String {
+    if payload.chars().count() > PAYLOAD_PREVIEW_LENGTH {
+        let mut preview: String = payload.chars().take(PAYLOAD_PREVIEW_LENGTH - 3).collect();
+        preview.push_str("...");
+        preview
+    } else {
+        payload.to_string()
+    }
+}
+
+fn format_row(time: &DateTime<FixedOffset>, topic: &str, payload: &str) -> String {
+    format!(
+        "{:<25}  {:<30}  {}",
+        time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        topic,
+        payload_preview(payload)
+    )
+}
+
+/// A message of the schedule, as dumped (or recorded when it is sent) in JSON.
+#[derive(Debug, Serialize)]
+pub(crate) struct ScheduledMessage<'a> {
+    time: String,
+    topic: &'a str,
+    payload: String,
+    qos: i32,
+    retain: bool,
+}
+
+impl<'a> ScheduledMessage<'a> {
+    /// Describes a message that is due at the given time, with the QoS used if it does not set one.
+    pub(crate) fn new(m: &'a Message, time: DateTime<FixedOffset>, qos: i32) -> Self {
+        Self {
+            time: time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            topic: &m.topic,
+            payload: match &m.payload_file {
+                Some(path) => format!("file:{}", path.display()),
+                None => m.message.to_string_lossy(),
+            },
+            qos: m.qos.unwrap_or(qos),
+            retain: m.retain,
+        }
+    }
+}
+
+/// Serializes the messages that have a scheduled time as a JSON array.
+fn schedule_json(messages: &[Message], qos: i32) -> Result<String> {
+    let schedule: Vec<_> = messages
+        .iter()
+        .filter_map(|m| {
+            m.timestamp
+                .scheduled_time()
+                .map(|time| ScheduledMessage::new(m, time, qos))
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&schedule)?)
+}
+
+/// Prints the messages due to be sent within the configured horizon, as a table or as JSON.
+pub(crate) fn run(args: &Cli) -> Result<()> {
+    // Listing is not repeated, so files that were modified recently are loaded straight away
+    let options = ScriptOptions {
+        settle_time: std::time::Duration::ZERO,
+        ..processing::script_options(args)
+    };
+    let script = Script::new(args.script_source_dir(), options)?;
+
+    let end = script.last_poll_time() + args.list_horizon;
+    let messages = script.upcoming(end, MAX_OCCURRENCES);
+
+    if args.dump_json {
+        println!("{}", schedule_json(&messages, args.mqtt_qos)?);
+        return Ok(());
+    }
+
+    println!("{:<25}  {:<30}  PAYLOAD", "TIME", "TOPIC");
+    for m in &messages {
+        if let Some(time) = m.timestamp.scheduled_time() {
+            println!(
+                "{}",
+                format_row(&time, &m.topic, &m.message.to_string_lossy())
+            );
+        }
+    }
+    println!(
+        "{} messages until {}",
+        messages.len(),
+        end.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Timestamp;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn preview_short_payload() {
+        assert_eq!(payload_preview("Hello, World!"), "Hello, World!");
+    }
+
+    #[test]
+    fn preview_long_payload() {
+        let payload = "x".repeat(100);
+        let preview = payload_preview(&payload);
+        assert_eq!(preview.chars().count(), PAYLOAD_PREVIEW_LENGTH);
+        assert!(preview.ends_with("..."));
+    }
+
+    #[test]
+    fn schedule_as_json() {
+        let t = Utc
+            .with_ymd_and_hms(2022, 3, 28, 10, 23, 33)
+            .unwrap()
+            .fixed_offset();
+        let message = |topic: &str, qos, retain| Message {
+            retain,
+            qos,
+            ..Message::new(Timestamp::Absolute(t), topic, "Hello, World!")
+        };
+        let on_connect = Message {
+            timestamp: Timestamp::OnConnect,
+            ..message("example/on_connect", None, false)
+        };
+
+        let json: serde_json::Value = serde_json::from_str(
+            &schedule_json(
+                &[
+                    message("example/one", None, false),
+                    message("example/two", Some(2), true),
+                    on_connect,
+                ],
+                1,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "time": "2022-03-28T10:23:33Z",
+                    "topic": "example/one",
+                    "payload": "Hello, World!",
+                    "qos": 1,
+                    "retain": false
+                },
+                {
+                    "time": "2022-03-28T10:23:33Z",
+                    "topic": "example/two",
+                    "payload": "Hello, World!",
+                    "qos": 2,
+                    "retain": true
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn row() {
+        let time = Utc
+            .with_ymd_and_hms(2022, 3, 28, 10, 23, 33)
+            .unwrap()
+            .fixed_offset();
+        assert_eq!(
+            format_row(&time, "example/one", "Hello, World!"),
+            "2022-03-28T10:23:33Z       example/one                     Hello, World!"
+        );
+    }
+}
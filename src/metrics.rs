@@ -0,0 +1,153 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Upper bounds (inclusive) of the payload size histogram buckets, in bytes.
+/// Larger payloads are counted in a final, unbounded bucket.
+const PAYLOAD_SIZE_BUCKETS: [usize; 7] = [16, 64, 256, 1024, 4096, 16384, 65536];
+
+/// Counts of published messages, by topic and by payload size.
+struct Metrics {
+    /// Number of topic levels messages are counted by, all levels if None
+    topic_depth: Option<usize>,
+    published: BTreeMap<String, u64>,
+    payload_sizes: [u64; PAYLOAD_SIZE_BUCKETS.len() + 1],
+    payload_bytes: u64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            topic_depth: None,
+            published: BTreeMap::new(),
+            payload_sizes: [0; PAYLOAD_SIZE_BUCKETS.len() + 1],
+            payload_bytes: 0,
+        }
+    }
+
+    fn record_publish(&mut self, topic: &str, payload_size: usize) {
+        *self
+            .published
+            .entry(topic_prefix(topic, self.topic_depth))
+            .or_default() += 1;
+
+        let bucket = PAYLOAD_SIZE_BUCKETS
+            .iter()
+            .position(|&le| payload_size <= le)
+            .unwrap_or(PAYLOAD_SIZE_BUCKETS.len());
+        self.payload_sizes[bucket] += 1;
+        self.payload_bytes += payload_size as u64;
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            published_by_topic: self.published.clone(),
+            payload_size: PayloadSizeHistogram {
+                buckets: self
+                    .payload_sizes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &count)| Bucket {
+                        le: PAYLOAD_SIZE_BUCKETS.get(i).copied(),
+                        count,
+                    })
+                    .collect(),
+                count: self.payload_sizes.iter().sum(),
+                sum: self.payload_bytes,
+            },
+        }
+    }
+}
+
+/// The first `depth` levels of a topic, which messages are counted by so that the number of
+/// counters stays bounded.
+fn topic_prefix(topic: &str, depth: Option<usize>) -> String {
+    match depth {
+        Some(depth) => topic.split('/').take(depth).collect::<Vec<_>>().join("/"),
+        None => topic.to_string(),
+    }
+}
+
+/// Published message counts, as reported in the status.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct MetricsSnapshot {
+    published_by_topic: BTreeMap<String, u64>,
+    payload_size: PayloadSizeHistogram,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PayloadSizeHistogram {
+    buckets: Vec<Bucket>,
+    count: u64,
+    sum: u64,
+}
+
+/// Number of payloads of at most `le` bytes that are larger than the previous bucket, `le` is
+/// None for the final, unbounded bucket.
+#[derive(Debug, PartialEq, Serialize)]
+struct Bucket {
+    le: Option<usize>,
+    count: u64,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+/// Sets the number of topic levels messages are counted by.
+pub(crate) fn set_topic_depth(depth: Option<usize>) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.topic_depth = depth;
+    }
+}
+
+/// Records a message that was published to a broker.
+pub(crate) fn record_publish(topic: &str, payload_size: usize) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.record_publish(topic, payload_size);
+    }
+}
+
+pub(crate) fn snapshot() -> Option<MetricsSnapshot> {
+    METRICS.lock().ok().map(|metrics| metrics.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn published_by_topic() {
+        let mut metrics = Metrics::new();
+        metrics.record_publish("home/kitchen/light", 2);
+        metrics.record_publish("home/kitchen/light", 3);
+        metrics.record_publish("home/hall/light", 100);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.published_by_topic["home/kitchen/light"], 2);
+        assert_eq!(snapshot.published_by_topic["home/hall/light"], 1);
+        assert_eq!(snapshot.payload_size.count, 3);
+        assert_eq!(snapshot.payload_size.sum, 105);
+        assert_eq!(snapshot.payload_size.buckets[0].count, 2);
+        assert_eq!(snapshot.payload_size.buckets[2].count, 1);
+        assert_eq!(snapshot.payload_size.buckets.last().unwrap().le, None);
+
+        let mut metrics = Metrics::new();
+        metrics.topic_depth = Some(1);
+        metrics.record_publish("home/kitchen/light", 2);
+        metrics.record_publish("home/hall/light", 100_000);
+        metrics.record_publish("garden", 2);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot.published_by_topic,
+            BTreeMap::from([("home".to_string(), 2), ("garden".to_string(), 1)])
+        );
+        assert_eq!(snapshot.payload_size.buckets.last().unwrap().count, 1);
+    }
+
+    #[test]
+    fn topic_prefixes() {
+        assert_eq!(topic_prefix("a/b/c", None), "a/b/c");
+        assert_eq!(topic_prefix("a/b/c", Some(2)), "a/b");
+        assert_eq!(topic_prefix("a/b/c", Some(5)), "a/b/c");
+        assert_eq!(topic_prefix("/a/b", Some(2)), "/a");
+    }
+}
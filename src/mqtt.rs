@@ -1,64 +1,1794 @@
-use super::{Cli, Event};
-use anyhow::Result;
+use super::{control, metrics, recv_event, script, warnings, Cli, Event};
+use anyhow::{anyhow, Result};
 use paho_mqtt::{
-    AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder, Message, PersistenceType,
+    AsyncClient, ConnectOptions, ConnectOptionsBuilder, CreateOptions, CreateOptionsBuilder,
+    Message, MessageBuilder, PersistenceType, Properties, PropertyCode, ReasonCode, SslOptions,
 };
-use std::time::Duration;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
-
-pub(crate) fn run(tx: Sender<Event>, args: &Cli) -> Result<JoinHandle<()>> {
-    let client = AsyncClient::new(
-        CreateOptionsBuilder::new()
-            .server_uri(&args.mqtt_broker)
-            .client_id(&args.mqtt_client_id)
-            .persistence(PersistenceType::None)
-            .finalize(),
-    )?;
+use serde::Serialize;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::Sender, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+/// Minimum time to wait before reconnecting to a broker, doubled for each failed attempt.
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum time to wait before reconnecting to a broker.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Time to wait for the warmup message to be acknowledged if there is no publish timeout.
+const WARMUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection state of a single broker, shared with the client callbacks.
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    connected: AtomicBool,
+    /// Number of times a connection was established, including reconnects
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    reconnect_attempts: AtomicU64,
+    /// Set once the client disconnects on exit, after which it is not reconnected
+    stopped: AtomicBool,
+    /// Set while the client is being reconnected, so that only one reconnect runs at a time
+    reconnecting: AtomicBool,
+}
+
+impl ConnectionState {
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn connects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn disconnects(&self) -> u64 {
+        self.disconnects.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reconnect_attempts(&self) -> u64 {
+        self.reconnect_attempts.load(Ordering::Relaxed)
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the client as being reconnected, returning false if it already is.
+    fn start_reconnecting(&self) -> bool {
+        !self.reconnecting.swap(true, Ordering::AcqRel)
+    }
+
+    fn stop_reconnecting(&self) {
+        self.reconnecting.store(false, Ordering::Release);
+    }
+
+    /// Records an attempt to reconnect after the connection was lost, `attempt` counts from 1 for
+    /// each disconnect.
+    fn on_reconnect_attempt(&self, broker: &str, attempt: u32, backoff: Duration) {
+        let total = self.reconnect_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        log::info!(
+            "Reconnecting to broker {} in {:?}, attempt {} ({} attempts so far)",
+            broker,
+            backoff,
+            attempt,
+            total
+        );
+    }
+
+    fn on_connected(&self, broker: &str) {
+        match self.disconnects() {
+            0 => log::info!("Connected to broker {}", broker),
+            n => log::info!("Reconnected to broker {} after {} disconnects", broker, n),
+        }
+        self.connected.store(true, Ordering::Relaxed);
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
 
-    client.set_connected_callback(|_| {
-        log::info!("Connected to broker");
+    /// Records the connection being lost, which is only counted once if it is reported more than
+    /// once (e.g. by both the disconnected and the connection lost callbacks).
+    fn on_disconnected(&self, broker: &str, reason: &str) {
+        if !self.connected.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let disconnects = self.disconnects.fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!(
+            "Disconnected from broker {} ({}), {} disconnects so far",
+            broker,
+            reason,
+            disconnects
+        );
+    }
+}
+
+/// Parses a WebSocket HTTP header given in the form "Name: Value".
+pub(crate) fn parse_ws_header(s: &str) -> Result<(String, String)> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(anyhow!(
+            "Invalid header \"{}\", expected the form \"Name: Value\"",
+            s
+        )),
+    }
+}
+
+/// Time to wait before a reconnect attempt, counted from 1, doubling from the minimum backoff up to
+/// the maximum. Up to half of the backoff is taken off at random (`jitter` from 0 to 1), so that
+/// several clients that lost their connection at once do not all reconnect at the same time.
+fn reconnect_backoff(attempt: u32, jitter: f64) -> Duration {
+    let backoff = RECONNECT_MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RECONNECT_MAX_BACKOFF);
+    backoff.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Random number from 0 to 1, without needing a random number generator.
+fn random_jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Starts reconnecting to a broker in the background, unless it is already being reconnected.
+fn spawn_reconnect(client: &AsyncClient, args: &Cli, broker: &str, state: &Arc<ConnectionState>) {
+    if !state.start_reconnecting() {
+        log::debug!("Already reconnecting to broker {}", broker);
+        return;
+    }
+
+    let (client, args, broker, state) = (
+        client.clone(),
+        args.clone(),
+        broker.to_string(),
+        state.clone(),
+    );
+    std::thread::spawn(move || {
+        reconnect(&client, &args, &broker, &state);
+        state.stop_reconnecting();
     });
+}
 
-    let response = client
-        .connect(
-            ConnectOptionsBuilder::new()
-                .automatic_reconnect(Duration::from_secs(1), Duration::from_secs(5))
-                .keep_alive_interval(Duration::from_secs(5))
-                .user_name(&args.mqtt_username)
-                .password(&args.mqtt_password)
-                .finalize(),
-        )
-        .wait()?;
+/// Reconnects to a broker after the connection was lost, until it is connected or stopped.
+/// Options are built for each attempt, so that rotated credentials are picked up.
+fn reconnect(client: &AsyncClient, args: &Cli, broker: &str, state: &ConnectionState) {
+    for attempt in 1.. {
+        let backoff = reconnect_backoff(attempt, random_jitter());
+        state.on_reconnect_attempt(broker, attempt, backoff);
+        std::thread::sleep(backoff);
+
+        if state.is_connected() || state.is_stopped() {
+            return;
+        }
+
+        let result =
+            connect_options(args, broker).and_then(|options| Ok(client.connect(options).wait()?));
+        match result {
+            Ok(_) => return,
+            Err(e) => log::warn!("Failed to reconnect to broker {}: {}", broker, e),
+        }
+    }
+}
+
+/// Parses an MQTT v5 user property given in the form "name=value".
+pub(crate) fn parse_user_property(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(anyhow!(
+            "Invalid property \"{}\", expected the form \"name=value\"",
+            s
+        )),
+    }
+}
+
+fn is_websocket_uri(uri: &str) -> bool {
+    uri.starts_with("ws://") || uri.starts_with("wss://")
+}
+
+fn is_tls_uri(uri: &str) -> bool {
+    uri.starts_with("ssl://") || uri.starts_with("wss://")
+}
+
+/// Value appended to the client ID, so that several instances can connect to the same broker.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub(crate) enum ClientIdSuffix {
+    Hostname,
+    Pid,
+    Broker,
+}
+
+/// Parses the client ID to use for a broker, given in the form "broker=client_id".
+pub(crate) fn parse_broker_client_id(s: &str) -> Result<(String, String)> {
+    match s.rsplit_once('=') {
+        Some((broker, client_id)) if !broker.trim().is_empty() && !client_id.trim().is_empty() => {
+            Ok((broker.trim().to_string(), client_id.trim().to_string()))
+        }
+        _ => Err(anyhow!(
+            "Invalid broker client ID \"{}\", expected the form \"broker=client_id\"",
+            s
+        )),
+    }
+}
+
+fn hostname() -> Result<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .chain(std::env::var("HOSTNAME"))
+        .chain(std::env::var("COMPUTERNAME"))
+        .map(|hostname| hostname.trim().to_string())
+        .find(|hostname| !hostname.is_empty())
+        .ok_or_else(|| anyhow!("Failed to determine hostname"))
+}
+
+/// Builds a client ID from the base client ID followed by each suffix, separated by "-".
+fn build_client_id(
+    base: &str,
+    suffixes: &[ClientIdSuffix],
+    hostname: &str,
+    pid: u32,
+    broker_index: usize,
+) -> String {
+    std::iter::once(base.to_string())
+        .chain(suffixes.iter().map(|suffix| match suffix {
+            ClientIdSuffix::Hostname => hostname.to_string(),
+            ClientIdSuffix::Pid => pid.to_string(),
+            ClientIdSuffix::Broker => broker_index.to_string(),
+        }))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Client ID to use for each broker, in the order they were given.
+/// A client ID given for a broker is used as is, otherwise the (suffixed) client ID is used.
+fn client_ids(args: &Cli) -> Result<Vec<String>> {
+    let hostname = if args
+        .mqtt_client_id_suffix
+        .contains(&ClientIdSuffix::Hostname)
+    {
+        hostname()?
+    } else {
+        String::new()
+    };
+
+    Ok(args
+        .mqtt_broker
+        .iter()
+        .enumerate()
+        .map(|(index, broker)| {
+            match args
+                .mqtt_broker_client_ids
+                .iter()
+                .find(|(b, _)| b == broker)
+            {
+                Some((_, client_id)) => client_id.clone(),
+                None => build_client_id(
+                    &args.mqtt_client_id,
+                    &args.mqtt_client_id_suffix,
+                    &hostname,
+                    std::process::id(),
+                    index,
+                ),
+            }
+        })
+        .collect())
+}
+
+/// Reads a secret (e.g. a Docker or Kubernetes secret) from a file, without the trailing newline.
+fn read_secret_file(path: &Path) -> Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Resolves the username and password to connect with, values read from files take precedence.
+/// This is called on every (re)connect, including on `SIGUSR1` or a reconnect command, so that
+/// credentials rotated in their files are picked up.
+fn credentials(args: &Cli) -> Result<(String, String)> {
+    let username = match &args.mqtt_username_file {
+        Some(path) => read_secret_file(path)?,
+        None => args.mqtt_username.clone(),
+    };
+
+    let password = match &args.mqtt_password_file {
+        Some(path) => read_secret_file(path)?,
+        None => args.mqtt_password.clone(),
+    };
+
+    Ok((username, password))
+}
+
+/// Builds the last will message, which the broker publishes if the connection is lost without
+/// disconnecting. User properties are only sent with MQTT v5.
+fn will_message(args: &Cli) -> Option<Message> {
+    let topic = args.mqtt_lwt_topic.as_ref()?;
+
+    let mut properties = Properties::new();
+    if args.mqtt_version == Some(5) {
+        for (name, value) in &args.mqtt_lwt_properties {
+            if let Err(e) = properties.push_string_pair(PropertyCode::UserProperty, name, value) {
+                log::error!("Failed to set will user property {}: {}", name, e);
+            }
+        }
+    } else if !args.mqtt_lwt_properties.is_empty() {
+        log::warn!("Will user properties require MQTT v5 (--mqtt-version 5) and are ignored");
+    }
+
+    Some(
+        MessageBuilder::new()
+            .topic(topic)
+            .payload(args.mqtt_lwt_payload.as_str())
+            .qos(args.mqtt_qos)
+            .retained(args.mqtt_lwt_retain)
+            .properties(properties)
+            .finalize(),
+    )
+}
+
+fn connect_options(args: &Cli, broker: &str) -> Result<ConnectOptions> {
+    let (username, password) = credentials(args)?;
+
+    let mut builder = ConnectOptionsBuilder::new();
+    // Reconnecting is done by the reconnect function rather than the client, so that attempts are
+    // counted and their backoff is jittered
+    builder
+        .keep_alive_interval(Duration::from_secs(5))
+        .user_name(username)
+        .password(password);
 
+    if let Some(version) = args.mqtt_version {
+        builder.mqtt_version(version);
+    }
+
+    // MQTT v5 replaces the clean session flag with clean start
+    if args.mqtt_version == Some(5) {
+        builder.clean_start(args.mqtt_clean_session);
+    } else {
+        builder.clean_session(args.mqtt_clean_session);
+    }
+
+    if is_tls_uri(broker) {
+        builder.ssl_options(SslOptions::new());
+    }
+
+    if let Some(will) = will_message(args) {
+        builder.will_message(will);
+    }
+
+    if !args.mqtt_ws_headers.is_empty() {
+        if is_websocket_uri(broker) {
+            builder.http_headers(&args.mqtt_ws_headers);
+        } else {
+            log::warn!("WebSocket headers are ignored for a non-WebSocket broker address");
+        }
+    }
+
+    Ok(builder.finalize())
+}
+
+/// Values of MQTT v5 message properties used for messages that do not set them.
+#[derive(Clone, Debug, Default)]
+struct PropertyDefaults {
+    expiry: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+}
+
+impl PropertyDefaults {
+    fn new(args: &Cli) -> Self {
+        Self {
+            expiry: args
+                .message_expiry
+                .map(|expiry| u32::try_from(expiry.as_secs()).unwrap_or(u32::MAX)),
+            content_type: args.content_type.clone(),
+            response_topic: args.response_topic.clone(),
+        }
+    }
+}
+
+/// Builds the MQTT message to publish for a scheduled message.
+/// The QoS is used unless the message sets its own. Properties fall back to the defaults if the
+/// message does not set them, they are only sent with MQTT v5 as earlier versions do not support
+/// properties.
+fn build_message(
+    msg: script::Message,
+    qos: i32,
+    defaults: &PropertyDefaults,
+    mqtt_version: i32,
+) -> Message {
+    let mut properties = Properties::new();
+
+    if let Some(expiry) = msg.expiry.or(defaults.expiry) {
+        if mqtt_version == 5 {
+            if let Err(e) = properties.push_u32(PropertyCode::MessageExpiryInterval, expiry) {
+                log::error!("Failed to set message expiry interval: {}", e);
+            }
+        } else {
+            log::debug!("Ignoring message expiry interval, it requires MQTT v5");
+        }
+    }
+
+    let string_properties = [
+        (
+            PropertyCode::ContentType,
+            "content type",
+            msg.content_type.as_ref().or(defaults.content_type.as_ref()),
+        ),
+        (
+            PropertyCode::ResponseTopic,
+            "response topic",
+            msg.response_topic
+                .as_ref()
+                .or(defaults.response_topic.as_ref()),
+        ),
+    ];
+    for (code, name, value) in string_properties {
+        if let Some(value) = value {
+            if mqtt_version == 5 {
+                if let Err(e) = properties.push_string(code, value) {
+                    log::error!("Failed to set {}: {}", name, e);
+                }
+            } else {
+                warnings::warn(format!(
+                    "Ignoring {} of message to {}, it requires MQTT v5",
+                    name, msg.topic
+                ));
+            }
+        }
+    }
+
+    MessageBuilder::new()
+        .topic(msg.topic)
+        .payload(msg.message)
+        .qos(msg.qos.unwrap_or(qos))
+        .retained(msg.retain)
+        .properties(properties)
+        .finalize()
+}
+
+/// Checks if publishing should wait for the delivery of a message to be confirmed.
+/// QoS 0 messages are never acknowledged by the broker, so there is nothing to wait for once they
+/// have been queued.
+fn waits_for_delivery(qos: i32) -> bool {
+    qos > 0
+}
+
+/// Checks if a message that could not be published may be accepted if it is sent again.
+/// Errors other than rejections by the broker (e.g. timeouts or a lost connection) are always worth
+/// retrying. Rejections (a PUBACK, or PUBREC and PUBCOMP for QoS 2, with an MQTT v5 error reason
+/// code) are only retried if the broker may accept the message later, or if the QoS 2 handshake
+/// did not complete, otherwise they are final.
+fn is_retryable(e: &paho_mqtt::Error) -> bool {
+    match e {
+        paho_mqtt::Error::ReasonCode(reason) => matches!(
+            reason,
+            ReasonCode::UnspecifiedError
+                | ReasonCode::ImplementationSpecificError
+                | ReasonCode::ServerBusy
+                | ReasonCode::QuotaExceeded
+                | ReasonCode::MessageRateTooHigh
+                | ReasonCode::PacketIdentifierInUse
+                | ReasonCode::PacketIdentifierNotFound
+        ),
+        _ => true,
+    }
+}
+
+/// Describes a message received from the broker, for logging.
+fn describe_received(msg: &Message) -> String {
+    format!(
+        "topic {}, QoS {}, {} bytes",
+        msg.topic(),
+        msg.qos(),
+        msg.payload().len()
+    )
+}
+
+/// Topic alias to publish a message with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TopicAlias {
+    /// Alias that was just assigned, the message carries both the topic and the alias
+    New(u16),
+    /// Alias that the broker already knows, the message only carries the alias
+    Known(u16),
+}
+
+/// MQTT v5 topic aliases assigned to topics on the current connection to a broker.
+/// Aliases are assigned to topics in the order they are first published to, until the maximum
+/// number of aliases is in use, so the topics of frequent (e.g. repeating) messages are only sent
+/// once per connection.
+#[derive(Debug, Default)]
+struct TopicAliases {
+    max: u16,
+    aliases: HashMap<String, u16>,
+}
+
+impl TopicAliases {
+    /// Sets the maximum number of aliases, dropping all assigned aliases.
+    fn set_max(&mut self, max: u16) {
+        self.max = max;
+        self.aliases.clear();
+    }
+
+    /// Alias to publish a message to a topic with, a new one if there is one left.
+    /// A new alias is only assigned to the topic once it is committed, so that it is not used
+    /// before the broker has been sent the message introducing it.
+    fn resolve(&self, topic: &str) -> Option<TopicAlias> {
+        if let Some(alias) = self.aliases.get(topic) {
+            return Some(TopicAlias::Known(*alias));
+        }
+
+        let alias = u16::try_from(self.aliases.len() + 1).ok()?;
+        (alias <= self.max).then_some(TopicAlias::New(alias))
+    }
+
+    /// Assigns a new alias to a topic, once the message introducing it has been queued.
+    fn commit(&mut self, topic: &str, alias: TopicAlias) {
+        if let TopicAlias::New(alias) = alias {
+            self.aliases.insert(topic.to_string(), alias);
+        }
+    }
+
+    /// Forgets the assigned aliases, which only last as long as the connection.
+    fn clear(&mut self) {
+        self.aliases.clear();
+    }
+}
+
+/// Rebuilds a message to be published with a topic alias, without the topic if the broker already
+/// knows the alias.
+fn with_topic_alias(msg: &Message, alias: TopicAlias) -> paho_mqtt::Result<Message> {
+    let (alias, topic) = match alias {
+        TopicAlias::New(alias) => (alias, msg.topic().to_string()),
+        TopicAlias::Known(alias) => (alias, String::new()),
+    };
+
+    let mut properties = msg.properties().clone();
+    properties.push_int(PropertyCode::TopicAlias, alias.into())?;
+
+    Ok(MessageBuilder::new()
+        .topic(topic)
+        .payload(msg.payload())
+        .qos(msg.qos())
+        .retained(msg.retained())
+        .properties(properties)
+        .finalize())
+}
+
+/// Passes a command received on the control topic on to the other tasks.
+/// A reconnect command is handled like `SIGUSR1`.
+fn handle_command(tx: &Sender<Event>, broker: &str, msg: &Message) {
+    match control::parse_command(&msg.payload_str()) {
+        Ok(command) => {
+            log::info!("Received command from {}: {:?}", broker, command);
+            let event = match command {
+                control::Command::Reconnect => Event::Reconnect,
+                command => Event::Control(command),
+            };
+            if let Err(e) = tx.send(event) {
+                log::error!("Failed to send control event: {}", e);
+            }
+        }
+        Err(e) => log::warn!("Ignoring invalid command from {}: {}", broker, e),
+    }
+}
+
+/// Publishes a message, waiting for its delivery to be confirmed if the QoS requires it.
+/// For QoS 2 the delivery is confirmed once the whole PUBLISH, PUBREC, PUBREL, PUBCOMP handshake
+/// has completed, a rejection by the broker at any step is an error.
+async fn publish(
+    client: &AsyncClient,
+    msg: Message,
+    publish_timeout: Option<Duration>,
+    topic_aliases: &Mutex<TopicAliases>,
+) -> paho_mqtt::Result<()> {
+    let qos = msg.qos();
+    let delivery_token = {
+        // Held until the message is queued, so that the message assigning an alias is always
+        // queued before the messages using it
+        let mut topic_aliases = topic_aliases.lock().unwrap();
+        let aliased = topic_aliases.resolve(msg.topic()).and_then(|alias| {
+            match with_topic_alias(&msg, alias) {
+                Ok(aliased) => Some((aliased, alias)),
+                Err(e) => {
+                    log::error!("Failed to set topic alias: {}", e);
+                    None
+                }
+            }
+        });
+        match aliased {
+            Some((aliased, alias)) => {
+                // An alias is only assigned if the message introducing it was queued, otherwise
+                // the broker would not know it
+                let delivery_token = client.try_publish(aliased)?;
+                topic_aliases.commit(msg.topic(), alias);
+                delivery_token
+            }
+            None => client.try_publish(msg)?,
+        }
+    };
+
+    if waits_for_delivery(qos) {
+        match publish_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, delivery_token)
+                .await
+                .map_err(|_| paho_mqtt::Error::Timeout)??,
+            None => delivery_token.await?,
+        };
+    }
+
+    Ok(())
+}
+
+/// Limits the number of messages that are in flight (published but not yet confirmed as
+/// delivered) to a broker at once.
+#[derive(Clone, Debug)]
+struct InFlight {
+    semaphore: Arc<Semaphore>,
+    limit: u32,
+}
+
+impl InFlight {
+    fn new(limit: u32) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            limit,
+        }
+    }
+
+    /// Waits until another message can be put in flight, it is in flight until the permit is
+    /// dropped.
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("in flight semaphore is never closed")
+    }
+
+    /// Waits until no message is in flight.
+    async fn wait_idle(&self) {
+        let _all = self
+            .semaphore
+            .acquire_many(self.limit)
+            .await
+            .expect("in flight semaphore is never closed");
+    }
+}
+
+/// Envelope of a message that could not be sent, published to the dead letter topic.
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    topic: &'a str,
+    payload: String,
+    error: String,
+    attempts: u32,
+}
+
+impl<'a> DeadLetter<'a> {
+    fn new(msg: &'a script::Message, error: &paho_mqtt::Error, attempts: u32) -> Self {
+        Self {
+            topic: &msg.topic,
+            payload: msg.message.to_string_lossy(),
+            error: error.to_string(),
+            attempts,
+        }
+    }
+}
+
+/// Publishes a message that could not be sent to the dead letter topic.
+/// This is only attempted once, a failure is logged but otherwise ignored.
+fn dead_letter(
+    client: &AsyncClient,
+    topic: &str,
+    msg: &script::Message,
+    error: &paho_mqtt::Error,
+    attempts: u32,
+    qos: i32,
+) {
+    let payload = match serde_json::to_string(&DeadLetter::new(msg, error, attempts)) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Failed to serialize dead letter: {}", e);
+            return;
+        }
+    };
+
+    match client.try_publish(Message::new(topic, payload, qos)) {
+        Ok(_) => log::info!("Message to {} sent to dead letter topic", msg.topic),
+        Err(e) => log::error!("Failed to send message to dead letter topic: {}", e),
+    }
+}
+
+/// Publishes scheduled messages to a single broker, retrying and dead lettering messages that
+/// could not be sent.
+struct Publisher {
+    client: AsyncClient,
+    broker: String,
+    qos: i32,
+    mqtt_version: i32,
+    publish_timeout: Option<Duration>,
+    max_retries: u32,
+    dead_letter_topic: Option<String>,
+    defaults: PropertyDefaults,
+    topic_aliases: Arc<Mutex<TopicAliases>>,
+    timeouts: AtomicU64,
+}
+
+impl Publisher {
+    async fn send(&self, msg: script::Message) {
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            let mqtt_msg = build_message(msg.clone(), self.qos, &self.defaults, self.mqtt_version);
+            match publish(
+                &self.client,
+                mqtt_msg,
+                self.publish_timeout,
+                &self.topic_aliases,
+            )
+            .await
+            {
+                Ok(()) => break Ok(()),
+                Err(e) => {
+                    if let paho_mqtt::Error::Timeout = e {
+                        let timeouts = self.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+                        log::error!(
+                            "Timed out waiting for delivery of message to {} ({} timeouts)",
+                            self.broker,
+                            timeouts
+                        );
+                    }
+                    if let paho_mqtt::Error::ReasonCode(reason) = &e {
+                        log::warn!(
+                            "Broker {} rejected message to {} (QoS {}): {}",
+                            self.broker,
+                            msg.topic,
+                            msg.qos.unwrap_or(self.qos),
+                            reason
+                        );
+                    }
+                    if attempts > self.max_retries || !is_retryable(&e) {
+                        break Err(e);
+                    }
+                    log::warn!(
+                        "Error sending message to {}, retrying ({}/{}): {}",
+                        self.broker,
+                        attempts,
+                        self.max_retries,
+                        e
+                    );
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                log::debug!("Message sent to {}", self.broker);
+                metrics::record_publish(&msg.topic, msg.message.len());
+            }
+            Err(e) => {
+                log::error!("Error sending message to {}: {}", self.broker, e);
+                if let Some(topic) = &self.dead_letter_topic {
+                    dead_letter(&self.client, topic, &msg, &e, attempts, self.qos);
+                }
+            }
+        }
+    }
+}
+
+/// Builds the warmup message, which carries the client ID so that it can be told apart.
+/// QoS 1 is used so that the broker has to acknowledge it.
+fn warmup_message(topic: &str, client_id: &str) -> Message {
+    MessageBuilder::new()
+        .topic(topic)
+        .payload(client_id)
+        .qos(1)
+        .finalize()
+}
+
+/// Publishes a warmup message and checks that the broker acknowledged it, which confirms that the
+/// broker accepts publishes from this client, rather than just its connection.
+/// Only MQTT v5 brokers can reject a message in the acknowledgement, MQTT v3 brokers may
+/// acknowledge messages that they then drop.
+fn warmup<F>(broker: &str, topic: &str, client_id: &str, publish: F) -> Result<()>
+where
+    F: FnOnce(Message) -> paho_mqtt::Result<()>,
+{
     log::info!(
-        "Using MQTT version {}",
-        response.connect_response().unwrap().mqtt_version
+        "Publishing warmup message to {} on broker {}",
+        topic,
+        broker
     );
+    publish(warmup_message(topic, client_id)).map_err(|e| match e {
+        paho_mqtt::Error::ReasonCode(reason) => anyhow!(
+            "Broker {} rejected the warmup message to {}: {}",
+            broker,
+            topic,
+            reason
+        ),
+        e => anyhow!(
+            "Warmup message to {} was not acknowledged by broker {}: {}",
+            topic,
+            broker,
+            e
+        ),
+    })?;
+    log::info!("Broker {} accepted the warmup message", broker);
+    Ok(())
+}
+
+/// A task publishing to a single broker.
+pub(crate) struct Broker {
+    pub uri: String,
+    pub state: Arc<ConnectionState>,
+    pub task: JoinHandle<()>,
+}
+
+pub(crate) fn run(tx: Sender<Event>, args: &Cli) -> Result<Vec<Broker>> {
+    let client_ids = client_ids(args)?;
+
+    let mut brokers = Vec::new();
+
+    let mut any_connected = false;
+
+    for (broker, client_id) in args.mqtt_broker.iter().zip(client_ids) {
+        log::info!("Using client ID {} for broker {}", client_id, broker);
+        match run_broker(tx.clone(), args, broker, &client_id) {
+            Ok((broker, connected)) => {
+                any_connected |= connected;
+                brokers.push(broker);
+            }
+            Err(e) => log::error!("Not using broker {}: {}", broker, e),
+        }
+    }
+
+    if any_connected {
+        Ok(brokers)
+    } else {
+        for broker in &brokers {
+            broker.state.stop();
+        }
+        Err(anyhow!("Failed to connect to any broker"))
+    }
+}
+
+/// Builds the options to create the client for a broker with.
+/// The client has to be created for MQTT v5 to connect with it, not just given the version in its
+/// connect options.
+fn create_options(args: &Cli, broker: &str, client_id: &str) -> CreateOptions {
+    let mut builder = CreateOptionsBuilder::new()
+        .server_uri(broker)
+        .client_id(client_id)
+        .persistence(PersistenceType::None);
+    if let Some(version) = args.mqtt_version {
+        builder = builder.mqtt_version(version);
+    }
+    builder.finalize()
+}
+
+/// Starts the task publishing to a broker, returning it along with whether the first connection
+/// attempt succeeded.
+/// A broker that can not be connected to at first is still used, it is reconnected to in the
+/// background like after a lost connection. A broker that rejects the warmup message is not used.
+fn run_broker(
+    tx: Sender<Event>,
+    args: &Cli,
+    broker: &str,
+    client_id: &str,
+) -> Result<(Broker, bool)> {
+    let client = AsyncClient::new(create_options(args, broker, client_id))?;
+
+    let broker = broker.to_string();
+    let state = Arc::new(ConnectionState::default());
+    let topic_aliases = Arc::new(Mutex::new(TopicAliases::default()));
+
+    {
+        let broker = broker.clone();
+        let state = state.clone();
+        let topic_aliases = topic_aliases.clone();
+        let debug_subscribe = args.debug_subscribe.clone();
+        let control_topic = args.control_topic.clone();
+        let qos = args.mqtt_qos;
+        let tx = tx.clone();
+        client.set_connected_callback(move |client| {
+            state.on_connected(&broker);
+            topic_aliases.lock().unwrap().clear();
+            if let Err(e) = tx.send(Event::Connected(broker.clone())) {
+                log::error!("Failed to send connected event: {}", e);
+            }
+            // Subscribing on every connect restores the subscription after a reconnect
+            if let Some(filter) = &debug_subscribe {
+                log::info!("Subscribing to {} on broker {}", filter, broker);
+                client.subscribe(filter, qos);
+            }
+            if let Some(topic) = &control_topic {
+                log::info!(
+                    "Subscribing to control topic {} on broker {}",
+                    topic,
+                    broker
+                );
+                client.subscribe(topic, qos);
+            }
+        });
+    }
+
+    if args.debug_subscribe.is_some() || args.control_topic.is_some() {
+        let broker = broker.clone();
+        let control_topic = args.control_topic.clone();
+        let tx = tx.clone();
+        client.set_message_callback(move |_, msg| {
+            if let Some(msg) = msg {
+                if control_topic.as_deref() == Some(msg.topic()) {
+                    handle_command(&tx, &broker, &msg);
+                } else {
+                    log::info!("Received from {}: {}", broker, describe_received(&msg));
+                }
+            }
+        });
+    }
+
+    {
+        let broker = broker.clone();
+        let state = state.clone();
+        let args = args.clone();
+        client.set_connection_lost_callback(move |client| {
+            state.on_disconnected(&broker, "connection lost");
+            spawn_reconnect(client, &args, &broker, &state);
+        });
+    }
+
+    {
+        let broker = broker.clone();
+        let state = state.clone();
+        let args = args.clone();
+        client.set_disconnected_callback(move |client, _, reason| {
+            state.on_disconnected(&broker, &format!("disconnected by broker: {}", reason));
+            spawn_reconnect(client, &args, &broker, &state);
+        });
+    }
+
+    let response = match client.connect(connect_options(args, &broker)?).wait() {
+        Ok(response) => Some(response),
+        Err(e) => {
+            log::error!("Failed to connect to broker {}: {}", broker, e);
+            spawn_reconnect(&client, args, &broker, &state);
+            None
+        }
+    };
+    let connected = response.is_some();
+    // Without a connection the requested version is assumed, or 3.1.1 which is tried first
+    let mqtt_version = match &response {
+        Some(response) => {
+            let version = response.connect_response().unwrap().mqtt_version;
+            log::info!("Using MQTT version {} with broker {}", version, broker);
+            version
+        }
+        None => args.mqtt_version.unwrap_or(4) as i32,
+    };
+
+    if args.topic_alias_max > 0 {
+        // The broker does not accept any topic aliases unless it gives a maximum, which is only
+        // known if the first connection succeeded
+        let broker_max = response
+            .as_ref()
+            .and_then(|response| {
+                response
+                    .properties()
+                    .get_int(PropertyCode::TopicAliasMaximum)
+            })
+            .unwrap_or(0);
+        let max = if mqtt_version == 5 {
+            u16::try_from(broker_max)
+                .unwrap_or(0)
+                .min(args.topic_alias_max)
+        } else {
+            0
+        };
+        log::info!("Using up to {} topic aliases with broker {}", max, broker);
+        topic_aliases.lock().unwrap().set_max(max);
+    }
+
+    if let Some(topic) = args.warmup_topic.as_ref().filter(|_| connected) {
+        let timeout = args.publish_timeout.unwrap_or(WARMUP_TIMEOUT);
+        let result = warmup(&broker, topic, client_id, |msg| {
+            client.publish(msg).wait_for(timeout).map(|_| ())
+        });
+        if let Err(e) = result {
+            state.stop();
+            if let Err(e) = client.disconnect(None).wait() {
+                log::warn!("Failed to disconnect from broker {}: {}", broker, e);
+            }
+            return Err(e);
+        }
+    }
 
     let mut rx = tx.subscribe();
-    let qos = args.mqtt_qos;
+    let args = args.clone();
+    let defaults = PropertyDefaults::new(&args);
+    if mqtt_version != 5 && (defaults.content_type.is_some() || defaults.response_topic.is_some()) {
+        log::warn!(
+            "Content type and response topic require MQTT v5, they are ignored for broker {}",
+            broker
+        );
+    }
+    let publisher = Arc::new(Publisher {
+        client: client.clone(),
+        broker: broker.clone(),
+        qos: args.mqtt_qos,
+        mqtt_version,
+        publish_timeout: args.publish_timeout,
+        max_retries: args.max_retries,
+        dead_letter_topic: args.dead_letter_topic.clone(),
+        defaults,
+        topic_aliases,
+        timeouts: AtomicU64::new(0),
+    });
+    let in_flight = InFlight::new(args.max_inflight);
+
+    let uri = broker.clone();
+    let task_state = state.clone();
+
+    let task = tokio::spawn(async move {
+        let state = task_state;
 
-    Ok(tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
+        while let Some(event) = recv_event(&mut rx, "MQTT").await {
             match event {
                 Event::Exit => {
+                    // Waiting for messages in flight, then disconnecting gives QoS 0 messages that
+                    // are still queued a chance to be sent
+                    in_flight.wait_idle().await;
+                    state.stop();
+                    if let Err(e) = client.disconnect(None).wait() {
+                        log::warn!("Failed to disconnect from broker {}: {}", broker, e);
+                    }
                     log::debug!("Task exit");
                     return;
                 }
-                Event::SendMessage(msg) => {
-                    match client.try_publish(Message::new(msg.topic, msg.message, qos)) {
-                        Ok(delivery_token) => {
-                            if let Err(e) = delivery_token.wait() {
-                                log::error!("Error sending message: {}", e);
-                            }
-                        }
+                Event::Reconnect => {
+                    let options = match connect_options(&args, &broker) {
+                        Ok(options) => options,
                         Err(e) => {
-                            log::error!("Error creating/queuing message: {}", e);
+                            log::error!("Not reconnecting to broker {}: {}", broker, e);
+                            continue;
                         }
+                    };
+                    if !state.start_reconnecting() {
+                        log::info!("Already reconnecting to broker {}", broker);
+                        continue;
+                    }
+                    log::info!("Reconnecting to broker {}", broker);
+                    // Messages in flight would otherwise fail when the connection is closed
+                    in_flight.wait_idle().await;
+                    if let Err(e) = client.disconnect(None).await {
+                        log::warn!("Failed to disconnect from broker {}: {}", broker, e);
+                    }
+                    state.on_disconnected(&broker, "reconnect requested");
+                    let result = client.connect(options).await;
+                    state.stop_reconnecting();
+                    if let Err(e) = result {
+                        log::error!("Failed to reconnect to broker {}: {}", broker, e);
+                        spawn_reconnect(&client, &args, &broker, &state);
                     }
                 }
+                Event::SendMessage(msg, target) => {
+                    if target.as_ref().is_some_and(|target| *target != broker) {
+                        continue;
+                    }
+                    if !state.is_connected() {
+                        log::warn!("Publishing to broker {} while disconnected", broker);
+                    }
+
+                    let permit = in_flight.acquire().await;
+                    let publisher = publisher.clone();
+                    tokio::spawn(async move {
+                        publisher.send(*msg).await;
+                        drop(permit);
+                    });
+                }
                 _ => {}
             }
         }
-    }))
+    });
+
+    Ok((Broker { uri, state, task }, connected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn connection_state() {
+        let state = ConnectionState::default();
+        assert!(!state.is_connected());
+        assert_eq!(state.disconnects(), 0);
+
+        state.on_connected("tcp://localhost:1883");
+        assert!(state.is_connected());
+
+        state.on_disconnected("tcp://localhost:1883", "connection lost");
+        assert!(!state.is_connected());
+        assert_eq!(state.disconnects(), 1);
+
+        state.on_connected("tcp://localhost:1883");
+        state.on_disconnected("tcp://localhost:1883", "connection lost");
+        assert_eq!(state.disconnects(), 2);
+
+        // A disconnect reported by more than one callback is counted once
+        state.on_disconnected("tcp://localhost:1883", "disconnected by broker");
+        assert_eq!(state.disconnects(), 2);
+    }
+
+    #[test]
+    fn single_reconnect() {
+        let state = ConnectionState::default();
+        assert!(state.start_reconnecting());
+        assert!(!state.start_reconnecting());
+        state.stop_reconnecting();
+        assert!(state.start_reconnecting());
+    }
+
+    #[test]
+    fn reconnect_attempts() {
+        let state = ConnectionState::default();
+        assert_eq!(state.reconnect_attempts(), 0);
+
+        state.on_disconnected("tcp://localhost:1883", "connection lost");
+        state.on_reconnect_attempt("tcp://localhost:1883", 1, Duration::from_secs(1));
+        state.on_reconnect_attempt("tcp://localhost:1883", 2, Duration::from_secs(2));
+        assert_eq!(state.reconnect_attempts(), 2);
+
+        // The count is a total across disconnects
+        state.on_connected("tcp://localhost:1883");
+        state.on_disconnected("tcp://localhost:1883", "connection lost");
+        state.on_reconnect_attempt("tcp://localhost:1883", 1, Duration::from_secs(1));
+        assert_eq!(state.reconnect_attempts(), 3);
+    }
+
+    #[test]
+    fn reconnect_backoff_jitter() {
+        assert_eq!(reconnect_backoff(1, 0.0), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(2, 0.0), Duration::from_secs(2));
+        assert_eq!(reconnect_backoff(3, 0.0), Duration::from_secs(4));
+        assert_eq!(reconnect_backoff(4, 0.0), Duration::from_secs(5));
+        assert_eq!(reconnect_backoff(100, 0.0), Duration::from_secs(5));
+
+        assert_eq!(reconnect_backoff(1, 1.0), Duration::from_millis(500));
+        assert_eq!(reconnect_backoff(4, 0.5), Duration::from_millis(3750));
+
+        for _ in 0..100 {
+            let jitter = random_jitter();
+            assert!((0.0..1.0).contains(&jitter));
+        }
+    }
+
+    #[test]
+    fn client_id_suffix() {
+        assert_eq!(
+            build_client_id("mqtt-actor", &[], "host", 42, 1),
+            "mqtt-actor"
+        );
+        assert_eq!(
+            build_client_id("mqtt-actor", &[ClientIdSuffix::Hostname], "host", 42, 1),
+            "mqtt-actor-host"
+        );
+        assert_eq!(
+            build_client_id(
+                "mqtt-actor",
+                &[ClientIdSuffix::Hostname, ClientIdSuffix::Pid],
+                "host",
+                42,
+                1
+            ),
+            "mqtt-actor-host-42"
+        );
+        assert_eq!(
+            build_client_id("mqtt-actor", &[ClientIdSuffix::Broker], "host", 42, 1),
+            "mqtt-actor-1"
+        );
+
+        let args = Cli::parse_from(["mqtt-actor", "--mqtt-client-id-suffix", "pid", "."]);
+        assert_eq!(
+            client_ids(&args).unwrap(),
+            vec![format!("mqtt-actor-{}", std::process::id())]
+        );
+    }
+
+    #[test]
+    fn broker_client_ids() {
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-broker",
+            "tcp://a:1883,tcp://b:1883,tcp://c:1883",
+            "--mqtt-client-id-suffix",
+            "broker",
+            "--mqtt-broker-client-id",
+            "tcp://b:1883=actor-b",
+            ".",
+        ]);
+        assert_eq!(
+            client_ids(&args).unwrap(),
+            vec!["mqtt-actor-0", "actor-b", "mqtt-actor-2"]
+        );
+
+        assert_eq!(
+            parse_broker_client_id("ws://a:8080/mqtt?x=1=actor").unwrap(),
+            ("ws://a:8080/mqtt?x=1".to_string(), "actor".to_string())
+        );
+        assert!(parse_broker_client_id("tcp://a:1883").is_err());
+        assert!(parse_broker_client_id("tcp://a:1883=").is_err());
+        assert!(parse_broker_client_id("=actor").is_err());
+    }
+
+    #[test]
+    fn qos_validation() {
+        for qos in ["0", "1", "2"] {
+            let args = Cli::try_parse_from(["mqtt-actor", "--mqtt-qos", qos, "."]).unwrap();
+            assert_eq!(args.mqtt_qos.to_string(), qos);
+        }
+
+        for qos in ["-1", "3", "5", "x"] {
+            assert!(Cli::try_parse_from(["mqtt-actor", "--mqtt-qos", qos, "."]).is_err());
+        }
+    }
+
+    #[test]
+    fn secret_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let username = dir.path().join("username");
+        let password = dir.path().join("password");
+        std::fs::write(&username, "user\n").unwrap();
+        std::fs::write(&password, "pass word\r\n").unwrap();
+
+        assert_eq!(read_secret_file(&username).unwrap(), "user");
+        assert_eq!(read_secret_file(&password).unwrap(), "pass word");
+
+        let missing = dir.path().join("missing");
+        let e = read_secret_file(&missing).unwrap_err();
+        assert!(e.to_string().contains("missing"));
+
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-username",
+            "inline",
+            "--mqtt-password",
+            "inline",
+            "--mqtt-password-file",
+            password.to_str().unwrap(),
+            ".",
+        ]);
+        assert_eq!(
+            credentials(&args).unwrap(),
+            ("inline".to_string(), "pass word".to_string())
+        );
+
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-username-file",
+            missing.to_str().unwrap(),
+            ".",
+        ]);
+        assert!(credentials(&args).is_err());
+    }
+
+    #[test]
+    fn ws_header_parse() {
+        assert_eq!(
+            parse_ws_header("Authorization: Bearer abc:123").unwrap(),
+            ("Authorization".to_string(), "Bearer abc:123".to_string())
+        );
+        assert!(parse_ws_header("Authorization").is_err());
+        assert!(parse_ws_header(": value").is_err());
+    }
+
+    #[test]
+    fn user_property_parse() {
+        assert_eq!(
+            parse_user_property("reason = offline=1").unwrap(),
+            ("reason".to_string(), "offline=1".to_string())
+        );
+        assert!(parse_user_property("reason").is_err());
+        assert!(parse_user_property("=offline").is_err());
+    }
+
+    #[test]
+    fn will_message_retained() {
+        let args = Cli::parse_from(["mqtt-actor", "."]);
+        assert!(will_message(&args).is_none());
+
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-qos",
+            "1",
+            "--mqtt-lwt-topic",
+            "status/actor",
+            "--mqtt-lwt-retain",
+            ".",
+        ]);
+        let will = will_message(&args).unwrap();
+        assert_eq!(will.topic(), "status/actor");
+        assert_eq!(will.payload_str(), "offline");
+        assert_eq!(will.qos(), 1);
+        assert!(will.retained());
+        assert!(connect_options(&args, &args.mqtt_broker[0]).is_ok());
+
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-lwt-topic",
+            "status/actor",
+            "--mqtt-lwt-payload",
+            "gone",
+            ".",
+        ]);
+        let will = will_message(&args).unwrap();
+        assert_eq!(will.payload_str(), "gone");
+        assert!(!will.retained());
+    }
+
+    #[test]
+    fn will_message_properties() {
+        let args = [
+            "mqtt-actor",
+            "--mqtt-lwt-topic",
+            "status/actor",
+            "--mqtt-lwt-property",
+            "reason=connection lost",
+        ];
+        let user_property =
+            |m: &Message| m.properties().get_string_pair(PropertyCode::UserProperty);
+
+        let v5 = Cli::parse_from(args.into_iter().chain(["--mqtt-version", "5", "."]));
+        assert_eq!(
+            user_property(&will_message(&v5).unwrap()),
+            Some(("reason".to_string(), "connection lost".to_string()))
+        );
+
+        let v4 = Cli::parse_from(args.into_iter().chain(["--mqtt-version", "4", "."]));
+        assert_eq!(user_property(&will_message(&v4).unwrap()), None);
+    }
+
+    #[test]
+    fn uri_schemes() {
+        assert!(is_websocket_uri("ws://localhost:8080/mqtt"));
+        assert!(is_websocket_uri("wss://localhost:8443/mqtt"));
+        assert!(!is_websocket_uri("tcp://localhost:1883"));
+
+        assert!(is_tls_uri("ssl://localhost:8883"));
+        assert!(is_tls_uri("wss://localhost:8443/mqtt"));
+        assert!(!is_tls_uri("ws://localhost:8080/mqtt"));
+    }
+
+    #[test]
+    fn connect_options_websocket() {
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-broker",
+            "wss://localhost:8443/mqtt",
+            "--mqtt-ws-header",
+            "X-Api-Key: secret",
+            ".",
+        ]);
+        let opts = format!(
+            "{:?}",
+            connect_options(&args, &args.mqtt_broker[0]).unwrap()
+        );
+        assert!(opts.contains("X-Api-Key"));
+        assert!(opts.contains("ssl: Some"));
+    }
+
+    #[test]
+    fn connect_options_tcp() {
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-broker",
+            "tcp://localhost:1883",
+            "--mqtt-ws-header",
+            "X-Api-Key: secret",
+            ".",
+        ]);
+        let opts = format!(
+            "{:?}",
+            connect_options(&args, &args.mqtt_broker[0]).unwrap()
+        );
+        assert!(!opts.contains("X-Api-Key"));
+        assert!(opts.contains("ssl: None"));
+    }
+
+    #[test]
+    fn connect_options_clean_session() {
+        let args = Cli::parse_from(["mqtt-actor", "."]);
+        let opts = connect_options(&args, &args.mqtt_broker[0]).unwrap();
+        assert!(opts.clean_session());
+        assert!(!opts.clean_start());
+
+        let args = Cli::parse_from(["mqtt-actor", "--mqtt-clean-session", "false", "."]);
+        let opts = connect_options(&args, &args.mqtt_broker[0]).unwrap();
+        assert!(!opts.clean_session());
+        assert!(!opts.clean_start());
+    }
+
+    #[test]
+    fn connect_options_clean_start() {
+        let args = Cli::parse_from(["mqtt-actor", "--mqtt-version", "5", "."]);
+        let opts = connect_options(&args, &args.mqtt_broker[0]).unwrap();
+        assert_eq!(opts.mqtt_version(), 5);
+        assert!(!opts.clean_session());
+        assert!(opts.clean_start());
+
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-version",
+            "5",
+            "--mqtt-clean-session",
+            "false",
+            ".",
+        ]);
+        let opts = connect_options(&args, &args.mqtt_broker[0]).unwrap();
+        assert_eq!(opts.mqtt_version(), 5);
+        assert!(!opts.clean_session());
+        assert!(!opts.clean_start());
+    }
+
+    #[test]
+    fn mqtt_version_client() {
+        for version in ["3", "4", "5"] {
+            let args = Cli::parse_from([
+                "mqtt-actor",
+                "--mqtt-broker",
+                "tcp://127.0.0.1:1",
+                "--mqtt-version",
+                version,
+                ".",
+            ]);
+            let broker = &args.mqtt_broker[0];
+            let client = AsyncClient::new(create_options(&args, broker, "test")).unwrap();
+            let options = connect_options(&args, broker).unwrap();
+
+            // Nothing is listening, so connecting fails, but only once it was attempted with the
+            // version (the client rejects connect options for a version it was not created for)
+            let e = client.connect(options).wait().unwrap_err();
+            assert!(!e.to_string().contains("Wrong MQTT version"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn multiple_brokers() {
+        let args = Cli::parse_from([
+            "mqtt-actor",
+            "--mqtt-broker",
+            "tcp://primary:1883",
+            "--mqtt-broker",
+            "wss://backup:8443/mqtt",
+            ".",
+        ]);
+        assert_eq!(
+            args.mqtt_broker,
+            vec!["tcp://primary:1883", "wss://backup:8443/mqtt"]
+        );
+        let opts = format!(
+            "{:?}",
+            connect_options(&args, &args.mqtt_broker[1]).unwrap()
+        );
+        assert!(opts.contains("ssl: Some"));
+    }
+
+    #[test]
+    fn qos_0_fast_path() {
+        assert!(!waits_for_delivery(0));
+        assert!(waits_for_delivery(1));
+        assert!(waits_for_delivery(2));
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit() {
+        let in_flight = InFlight::new(2);
+
+        let first = in_flight.acquire().await;
+        let second = in_flight.acquire().await;
+
+        let acquire = tokio::time::timeout(Duration::from_millis(50), in_flight.acquire());
+        assert!(acquire.await.is_err());
+        let idle = tokio::time::timeout(Duration::from_millis(50), in_flight.wait_idle());
+        assert!(idle.await.is_err());
+
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_millis(50), in_flight.acquire())
+            .await
+            .unwrap();
+
+        drop(second);
+        drop(third);
+        tokio::time::timeout(Duration::from_millis(50), in_flight.wait_idle())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn rejected_delivery() {
+        assert!(is_retryable(&paho_mqtt::Error::Timeout));
+        assert!(is_retryable(&paho_mqtt::Error::ReasonCode(
+            ReasonCode::QuotaExceeded
+        )));
+        // The QoS 2 handshake did not complete, so the message can be sent again
+        assert!(is_retryable(&paho_mqtt::Error::ReasonCode(
+            ReasonCode::PacketIdentifierNotFound
+        )));
+        assert!(!is_retryable(&paho_mqtt::Error::ReasonCode(
+            ReasonCode::NotAuthorized
+        )));
+        assert!(!is_retryable(&paho_mqtt::Error::ReasonCode(
+            ReasonCode::TopicNameInvalid
+        )));
+    }
+
+    #[test]
+    fn warmup_accepted() {
+        let mut published = None;
+        let result = warmup("tcp://localhost:1883", "health", "actor-1", |msg| {
+            published = Some(msg);
+            Ok(())
+        });
+        assert!(result.is_ok());
+
+        let msg = published.unwrap();
+        assert_eq!(msg.topic(), "health");
+        assert_eq!(msg.payload_str(), "actor-1");
+        assert_eq!(msg.qos(), 1);
+        assert!(!msg.retained());
+    }
+
+    #[test]
+    fn warmup_failed() {
+        let rejected = warmup("tcp://localhost:1883", "health", "actor-1", |_| {
+            Err(paho_mqtt::Error::ReasonCode(ReasonCode::NotAuthorized))
+        });
+        assert!(rejected.unwrap_err().to_string().contains("rejected"));
+
+        let timed_out = warmup("tcp://localhost:1883", "health", "actor-1", |_| {
+            Err(paho_mqtt::Error::Timeout)
+        });
+        assert!(timed_out
+            .unwrap_err()
+            .to_string()
+            .contains("not acknowledged"));
+    }
+
+    #[test]
+    fn control_commands() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel::<Event>(16);
+        let command = |payload: &str| Message::new("control", payload, 1);
+
+        handle_command(
+            &tx,
+            "tcp://localhost:1883",
+            &command(r#"{"command": "reconnect"}"#),
+        );
+        assert_eq!(rx.try_recv().unwrap(), Event::Reconnect);
+
+        let remove = r#"{"command": "remove", "topic": "a", "time": "2022-03-28T18:00:00Z"}"#;
+        handle_command(&tx, "tcp://localhost:1883", &command(remove));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Event::Control(control::Command::Remove { .. })
+        ));
+
+        handle_command(&tx, "tcp://localhost:1883", &command("reconnect"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn received_message_description() {
+        let msg = Message::new("test/topic", "hello", 1);
+        assert_eq!(describe_received(&msg), "topic test/topic, QoS 1, 5 bytes");
+    }
+
+    #[test]
+    fn dead_letter_envelope() {
+        let msg = script::Message::new(
+            script::Timestamp::Relative(chrono::Duration::zero()),
+            "test_topic",
+            "msg",
+        );
+
+        let envelope = DeadLetter::new(&msg, &paho_mqtt::Error::Timeout, 3);
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&envelope).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "topic": "test_topic",
+                "payload": "msg",
+                "error": paho_mqtt::Error::Timeout.to_string(),
+                "attempts": 3
+            })
+        );
+    }
+
+    #[test]
+    fn message_expiry() {
+        let msg = script::Message {
+            retain: true,
+            ..script::Message::new(
+                script::Timestamp::Relative(chrono::Duration::zero()),
+                "test_topic",
+                "msg",
+            )
+        };
+
+        let expiry = |m: &Message| {
+            m.properties()
+                .get_val::<u32>(PropertyCode::MessageExpiryInterval)
+        };
+
+        let m = build_message(msg.clone(), 1, &PropertyDefaults::default(), 5);
+        assert_eq!(m.topic(), "test_topic");
+        assert!(m.retained());
+        assert_eq!(expiry(&m), None);
+
+        let defaults = PropertyDefaults {
+            expiry: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            expiry(&build_message(msg.clone(), 1, &defaults, 5)),
+            Some(60)
+        );
+        assert_eq!(expiry(&build_message(msg.clone(), 1, &defaults, 4)), None);
+
+        let msg = script::Message {
+            expiry: Some(10),
+            ..msg
+        };
+        assert_eq!(expiry(&build_message(msg, 1, &defaults, 5)), Some(10));
+    }
+
+    #[test]
+    fn content_type_and_response_topic() {
+        let msg = script::Message::new(
+            script::Timestamp::Relative(chrono::Duration::zero()),
+            "test_topic",
+            "{}",
+        );
+
+        let properties = |m: &Message| {
+            (
+                m.properties().get_string(PropertyCode::ContentType),
+                m.properties().get_string(PropertyCode::ResponseTopic),
+            )
+        };
+
+        assert_eq!(
+            properties(&build_message(
+                msg.clone(),
+                1,
+                &PropertyDefaults::default(),
+                5
+            )),
+            (None, None)
+        );
+
+        let defaults = PropertyDefaults {
+            content_type: Some("application/json".into()),
+            response_topic: Some("replies/default".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            properties(&build_message(msg.clone(), 1, &defaults, 5)),
+            (
+                Some("application/json".into()),
+                Some("replies/default".into())
+            )
+        );
+        assert_eq!(
+            properties(&build_message(msg.clone(), 1, &defaults, 4)),
+            (None, None)
+        );
+
+        let msg = script::Message {
+            content_type: Some("text/plain".into()),
+            response_topic: Some("replies/test".into()),
+            ..msg
+        };
+        assert_eq!(
+            properties(&build_message(msg, 1, &defaults, 5)),
+            (Some("text/plain".into()), Some("replies/test".into()))
+        );
+    }
+
+    #[test]
+    fn topic_alias_assignment() {
+        let mut aliases = TopicAliases::default();
+        assert_eq!(aliases.resolve("a"), None);
+
+        aliases.set_max(2);
+        let mut resolve = |topic: &str| {
+            let alias = aliases.resolve(topic);
+            if let Some(alias) = alias {
+                aliases.commit(topic, alias);
+            }
+            alias
+        };
+        assert_eq!(resolve("a"), Some(TopicAlias::New(1)));
+        assert_eq!(resolve("a"), Some(TopicAlias::Known(1)));
+        assert_eq!(resolve("b"), Some(TopicAlias::New(2)));
+        assert_eq!(resolve("c"), None);
+        assert_eq!(resolve("b"), Some(TopicAlias::Known(2)));
+        assert_eq!(resolve("c"), None);
+
+        // Aliases are assigned again after a reconnect
+        aliases.clear();
+        assert_eq!(aliases.resolve("c"), Some(TopicAlias::New(1)));
+        aliases.commit("c", TopicAlias::New(1));
+        assert_eq!(aliases.resolve("a"), Some(TopicAlias::New(2)));
+        aliases.commit("a", TopicAlias::New(2));
+        assert_eq!(aliases.resolve("b"), None);
+    }
+
+    #[tokio::test]
+    async fn topic_alias_failed_publish() {
+        let client = AsyncClient::new(
+            CreateOptionsBuilder::new()
+                .server_uri("tcp://localhost:1883")
+                .persistence(PersistenceType::None)
+                .finalize(),
+        )
+        .unwrap();
+        let aliases = Mutex::new(TopicAliases::default());
+        aliases.lock().unwrap().set_max(2);
+        let msg = MessageBuilder::new()
+            .topic("test_topic")
+            .payload("msg")
+            .qos(1)
+            .finalize();
+
+        // The client is not connected, so the message introducing the alias is not queued and the
+        // alias is still new for the next message (e.g. a retry)
+        assert!(publish(&client, msg.clone(), None, &aliases).await.is_err());
+        assert!(publish(&client, msg, None, &aliases).await.is_err());
+        assert_eq!(
+            aliases.lock().unwrap().resolve("test_topic"),
+            Some(TopicAlias::New(1))
+        );
+    }
+
+    #[test]
+    fn topic_alias_message() {
+        let msg = MessageBuilder::new()
+            .topic("test_topic")
+            .payload("msg")
+            .qos(1)
+            .retained(true)
+            .finalize();
+        let alias = |m: &Message| m.properties().get_int(PropertyCode::TopicAlias);
+
+        let m = with_topic_alias(&msg, TopicAlias::New(3)).unwrap();
+        assert_eq!(m.topic(), "test_topic");
+        assert_eq!(alias(&m), Some(3));
+        assert_eq!(m.payload(), b"msg");
+        assert_eq!(m.qos(), 1);
+        assert!(m.retained());
+
+        let m = with_topic_alias(&msg, TopicAlias::Known(3)).unwrap();
+        assert_eq!(m.topic(), "");
+        assert_eq!(alias(&m), Some(3));
+        assert_eq!(m.payload(), b"msg");
+    }
 }
@@ -1,34 +1,1035 @@
-use super::{script::Script, Cli, Event};
-use anyhow::Result;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use super::{
+    control::Command,
+    metrics,
+    mqtt::ConnectionState,
+    recv_event,
+    script::{self, Cancellation, Message, Payload, Script, ScriptOptions, Timestamp},
+    status::{BrokerStatus, Status},
+    Cli, Event,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    fs,
+    ops::RangeInclusive,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{error::TryRecvError, Receiver, Sender},
+    task::JoinHandle,
+    time::{interval, sleep_until, Instant},
+};
 
-pub(crate) fn run(tx: Sender<Event>, args: &Cli) -> Result<JoinHandle<()>> {
+pub(crate) fn script_options(args: &Cli) -> ScriptOptions {
+    ScriptOptions {
+        delimiter: args.script_delimiter,
+        state_file: args.state_file.clone(),
+        warn_past: args.warn_past,
+        path_template: args.path_template.clone(),
+        strict: args.strict,
+        strict_start: args.strict_start,
+        default_topic: args.default_topic.clone(),
+        topic_aliases: args.topic_aliases.iter().cloned().collect(),
+        strict_env: args.strict_env,
+        topic_separator: args.topic_separator,
+        quote: (!args.no_quoting).then_some(args.quote_char as u8),
+        trim_quoted: args.trim_quoted,
+        timestamp_formats: args.timestamp_formats.clone(),
+        script_globs: args.script_glob.clone(),
+        ignore_patterns: args.ignore_pattern.clone(),
+        settle_time: args.settle_time,
+        loop_period: args.loop_period,
+        deadline: args.deadline,
+        relative_mode: args.relative_mode,
+        timezone: args.timezone,
+        log_loaded_messages: args.log_loaded_messages,
+        max_files: args.max_files,
+        fetcher: script::Fetcher::default(),
+    }
+}
+
+/// Bounds on the payload size of messages to topics starting with a prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PayloadSizeLimit {
+    prefix: String,
+    size: RangeInclusive<usize>,
+}
+
+/// Parses a payload size limit given in the form "prefix=min..max", in bytes, where either bound
+/// can be omitted (e.g. "sensors/=..1024").
+pub(crate) fn parse_payload_size_limit(s: &str) -> Result<PayloadSizeLimit> {
+    let invalid = || {
+        anyhow!(
+            "Invalid payload size limit \"{}\", expected the form \"prefix=min..max\"",
+            s
+        )
+    };
+
+    let (prefix, range) = s.rsplit_once('=').ok_or_else(invalid)?;
+    let (min, max) = range.split_once("..").ok_or_else(invalid)?;
+    let bound = |b: &str, default| match b.trim() {
+        "" => Ok(default),
+        b => b.parse::<usize>().map_err(|_| invalid()),
+    };
+    let size = bound(min, 0)?..=bound(max, usize::MAX)?;
+
+    if size.is_empty() {
+        return Err(anyhow!(
+            "Invalid payload size limit \"{}\", the minimum is greater than the maximum",
+            s
+        ));
+    }
+
+    Ok(PayloadSizeLimit {
+        prefix: prefix.trim().to_string(),
+        size,
+    })
+}
+
+/// Checks the payload size of a message against the limit with the longest prefix of its topic,
+/// if there is one.
+fn check_payload_size(message: &Message, limits: &[PayloadSizeLimit]) -> Result<()> {
+    let limit = limits
+        .iter()
+        .filter(|limit| message.topic.starts_with(&limit.prefix))
+        .max_by_key(|limit| limit.prefix.len());
+
+    match limit {
+        Some(limit) if !limit.size.contains(&message.message.len()) => Err(anyhow!(
+            "payload of {} bytes is outside the limits for topics starting with \"{}\"",
+            message.message.len(),
+            limit.prefix
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks if the condition file of a message is met, which is the case if it exists and is either
+/// empty or contains "true".
+fn condition_met(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let contents = contents.trim();
+            contents.is_empty() || contents.eq_ignore_ascii_case("true")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Applies the condition of a message, if it has one. If the condition is not met the message is
+/// sent to its else topic instead, or skipped (None) if it does not have one.
+fn apply_condition(message: Message) -> Option<Message> {
+    match &message.condition {
+        Some(path) if !condition_met(path) => {
+            let topic = message.else_topic.clone()?;
+            Some(Message { topic, ..message })
+        }
+        _ => Some(message),
+    }
+}
+
+/// Transformations applied to the payload of every message before it is sent.
+#[derive(Debug, Default)]
+struct PayloadTransform {
+    prefix: Payload,
+    suffix: Payload,
+    wrap_json: bool,
+}
+
+impl PayloadTransform {
+    fn new(args: &Cli) -> Self {
+        Self {
+            prefix: args.payload_prefix.clone().unwrap_or_default(),
+            suffix: args.payload_suffix.clone().unwrap_or_default(),
+            wrap_json: args.payload_wrap_json,
+        }
+    }
+
+    /// Wraps the payload in JSON if enabled, then surrounds it with the prefix and suffix.
+    fn apply(&self, message: Message) -> Result<Message> {
+        let payload = if self.wrap_json {
+            message.message.wrap_json()?
+        } else {
+            message.message
+        };
+        Ok(Message {
+            message: payload.surround(&self.prefix, &self.suffix),
+            ..message
+        })
+    }
+}
+
+/// Orders messages that are due in the same poll window by priority, highest first.
+/// Messages with the same priority keep their (timestamp) order.
+fn order_by_priority(messages: &mut [Message]) {
+    messages.sort_by_key(|m| Reverse(m.priority));
+}
+
+/// Order in which messages that are due in the same poll window are sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum PublishOrder {
+    /// By priority, then timestamp
+    #[default]
+    Timestamp,
+    /// One message per topic in turn, so no topic is starved by a burst of messages to another
+    RoundRobin,
+}
+
+/// Interleaves messages by topic, taking one message from each topic in turn.
+/// Topics are visited in the order they first appear and each topic keeps its own order.
+fn interleave_topics(messages: Vec<Message>) -> Vec<Message> {
+    let len = messages.len();
+
+    let mut queues: Vec<VecDeque<Message>> = Vec::new();
+    for message in messages {
+        match queues.iter_mut().find(|q| q[0].topic == message.topic) {
+            Some(queue) => queue.push_back(message),
+            None => queues.push(VecDeque::from([message])),
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(len);
+    while ordered.len() < len {
+        ordered.extend(queues.iter_mut().filter_map(|q| q.pop_front()));
+    }
+    ordered
+}
+
+/// Checks if a message was not sent within its validity period after its scheduled time.
+fn is_stale(message: &Message, now: DateTime<FixedOffset>) -> bool {
+    match (message.valid_for, message.timestamp.scheduled_time()) {
+        (Some(valid_for), Some(scheduled)) => {
+            now - scheduled > chrono::Duration::seconds(valid_for.into())
+        }
+        _ => false,
+    }
+}
+
+/// Builds a message published by the actor itself, rather than from the script.
+fn actor_message(topic: String, payload: &str) -> Message {
+    Message::new(Timestamp::Absolute(script::now()), topic, payload)
+}
+
+/// Publishes the complete message, if there is a complete topic that was not published to yet, and
+/// exits.
+fn finish(tx: &Sender<Event>, complete_topic: &mut Option<String>, complete_payload: &str) {
+    if let Some(topic) = complete_topic.take() {
+        let message = actor_message(topic, complete_payload);
+        log::info!("Sending complete message: {}", message);
+        if let Err(e) = tx.send(Event::SendMessage(Box::new(message), None)) {
+            log::error!("Failed to send complete message event: {}", e);
+        }
+    }
+    if let Err(e) = tx.send(Event::Exit) {
+        log::error!("Failed to send exit event: {}", e);
+    }
+}
+
+/// A message that is due and waiting to be sent, with the broker to send it to if it is only sent
+/// to one (i.e. an on connect message), otherwise it is sent to every broker.
+#[derive(Debug)]
+struct Outgoing {
+    message: Message,
+    broker: Option<String>,
+}
+
+impl From<Message> for Outgoing {
+    /// A message to send to every broker.
+    fn from(message: Message) -> Self {
+        Self {
+            message,
+            broker: None,
+        }
+    }
+}
+
+/// Tracks the connections to each broker that on connect messages have been queued for, so that
+/// they are queued once per connection.
+/// The connected event of a connection made before the processing task started may or may not be
+/// received, so connections are counted rather than events.
+#[derive(Debug, Default)]
+struct ConnectTracker(HashMap<String, u64>);
+
+impl ConnectTracker {
+    /// Checks if the latest connection to a broker is new, given the number of times it has
+    /// connected so far, recording it if it is.
+    fn is_new(&mut self, broker: &str, connects: u64) -> bool {
+        let handled = self.0.entry(broker.to_string()).or_default();
+        if connects > *handled {
+            *handled = connects;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Queues the on connect messages of the script to be sent to a broker only.
+fn queue_on_connect(script: &Script, pending: &mut VecDeque<Outgoing>, broker: &str) {
+    let messages = script.on_connect_messages();
+    if !messages.is_empty() {
+        log::info!(
+            "Connected to broker {}, sending {} on connect messages",
+            broker,
+            messages.len()
+        );
+        pending.extend(messages.into_iter().map(|message| Outgoing {
+            message,
+            broker: Some(broker.to_string()),
+        }));
+    }
+}
+
+/// Cancels the messages to a topic scheduled at a time, both those that are still scheduled and
+/// those that are due but waiting to be sent, returning the number of messages cancelled.
+/// Messages that have already been sent can not be cancelled.
+fn remove_message(
+    script: &mut Script,
+    pending: &mut VecDeque<Outgoing>,
+    topic: &str,
+    time: DateTime<FixedOffset>,
+) -> usize {
+    let waiting = pending.len();
+    pending.retain(|o| {
+        !(o.message.topic == topic && o.message.timestamp.scheduled_time() == Some(time))
+    });
+    let removed = waiting - pending.len();
+
+    match script.cancel(topic, time) {
+        Cancellation::Cancelled(count) => removed + count,
+        Cancellation::AlreadySent if removed == 0 => {
+            log::warn!(
+                "Not removing message to {} at {}, it has already been sent",
+                topic,
+                time.to_rfc3339()
+            );
+            0
+        }
+        Cancellation::NotFound if removed == 0 => {
+            log::warn!(
+                "Not removing message to {} at {}, there is no such message",
+                topic,
+                time.to_rfc3339()
+            );
+            0
+        }
+        _ => removed,
+    }
+}
+
+/// Checks if the actor should exit after the script was reloaded, which is the case if the
+/// script is now empty and exiting on an empty script is enabled.
+fn exit_after_reload(script: &Script, exit_on_empty: bool) -> bool {
+    if script.is_empty() {
+        log::info!("Script contains no messages after reload");
+        exit_on_empty
+    } else {
+        false
+    }
+}
+
+/// Drops ticks that queued up while the previous tick was handled (e.g. because sending was slow),
+/// as a single poll covers all the time since the last one. Other queued events are moved to
+/// `deferred`, in order, to be handled next.
+/// Returns the number of ticks dropped.
+fn coalesce_ticks(rx: &mut Receiver<Event>, deferred: &mut VecDeque<Event>) -> usize {
+    let mut dropped = 0;
+    loop {
+        match rx.try_recv() {
+            Ok(Event::Tick) => dropped += 1,
+            Ok(event) => deferred.push_back(event),
+            Err(TryRecvError::Lagged(n)) => {
+                log::warn!("Processing task fell behind, {} events were dropped", n);
+            }
+            Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return dropped,
+        }
+    }
+}
+
+/// Returns the next deferred event, if there is one, otherwise waits for the next event.
+async fn next_event(rx: &mut Receiver<Event>, deferred: &mut VecDeque<Event>) -> Option<Event> {
+    match deferred.pop_front() {
+        Some(event) => Some(event),
+        None => recv_event(rx, "Processing").await,
+    }
+}
+
+/// Holds off sending messages until a delay after the first connection to a broker.
+#[derive(Debug)]
+struct StartupGate {
+    delay: Duration,
+    open_at: Option<Instant>,
+}
+
+impl StartupGate {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            open_at: None,
+        }
+    }
+
+    /// Records a connection to a broker, only the first one opens the gate.
+    fn on_connected(&mut self, now: Instant) {
+        if self.open_at.is_none() {
+            if !self.delay.is_zero() {
+                log::info!("Delaying sending messages by {:?}", self.delay);
+            }
+            self.open_at = Some(now + self.delay);
+        }
+    }
+
+    /// Time from which messages can be sent, None until a broker has connected.
+    fn open_at(&self) -> Option<Instant> {
+        self.open_at
+    }
+}
+
+fn log_summary(script: &Script) {
+    let summary = script.summary();
+    match (summary.earliest, summary.latest) {
+        (Some(earliest), Some(latest)) => log::info!(
+            "{} messages scheduled, from {} to {}",
+            summary.count,
+            earliest.to_rfc3339(),
+            latest.to_rfc3339()
+        ),
+        _ => log::info!("{} messages scheduled", summary.count),
+    }
+}
+
+/// Runs the script, polling it on each [`Event::Tick`] and sending an [`Event::SendMessage`] for
+/// each message that is due. The script is reloaded on [`Event::ReloadScript`].
+pub fn run(
+    tx: Sender<Event>,
+    args: &Cli,
+    connections: Vec<(String, Arc<ConnectionState>)>,
+) -> Result<JoinHandle<()>> {
     let mut rx = tx.subscribe();
 
-    let mut script = Script::new(&args.script_source_dir, args.script_delimiter)?;
+    let mut script = Script::new(args.script_source_dir(), script_options(args))?;
+    let mut last_reload = script::now();
+    log_summary(&script);
+
+    let payload_size_limits = args.payload_size_limits.clone();
+    let payload_transform = PayloadTransform::new(args);
+    let once = args.once;
+    let exit_on_empty = args.exit_on_empty;
+    let strict_env = args.strict_env;
+    let publish_order = args.publish_order;
+    let mut complete_topic = args.complete_topic.clone();
+    let complete_payload = args.complete_payload.clone();
+    if once && script.is_unbounded() {
+        log::warn!("Script contains messages that repeat indefinitely, it will never finish");
+    }
+
+    // Minimum time between two consecutive sends, if a maximum rate is configured
+    let send_interval = args
+        .max_rate
+        .map(|rate| Duration::from_secs_f64(1.0 / rate));
+
+    let dry_run = args.dry_run;
+    let mut startup = StartupGate::new(args.startup_delay);
+    // Without brokers (in a dry run) there is no connection to wait for
+    if dry_run || connections.iter().any(|(_, state)| state.is_connected()) {
+        startup.on_connected(Instant::now());
+    }
+
+    let status_topic = args.status_topic.clone();
+    let mut status_interval = interval(args.status_interval);
+    let start = Instant::now();
 
     Ok(tokio::spawn(async move {
-        while let Ok(event) = rx.recv().await {
-            match event {
-                Event::Exit => {
-                    log::debug!("Task exit");
-                    return;
+        let mut pending = VecDeque::new();
+        let mut next_send = Instant::now();
+        let mut messages_sent: u64 = 0;
+        let mut deferred = VecDeque::new();
+
+        // The brokers are connected before this task starts, so their connected events may have
+        // been missed
+        let mut connects = ConnectTracker::default();
+        for (broker, state) in &connections {
+            if state.is_connected() && connects.is_new(broker, state.connects()) {
+                queue_on_connect(&script, &mut pending, broker);
+            }
+        }
+        // Without brokers (in a dry run) the on connect messages are only recorded, once
+        if dry_run {
+            pending.extend(script.on_connect_messages().into_iter().map(Outgoing::from));
+        }
+
+        loop {
+            tokio::select! {
+                event = next_event(&mut rx, &mut deferred) => {
+                    match event {
+                        Some(Event::Exit) | None => {
+                            log::debug!("Task exit");
+                            return;
+                        }
+                        Some(Event::ReloadScript) => {
+                            match script.reload() {
+                                Ok(()) => {
+                                    last_reload = script::now();
+                                    log_summary(&script);
+                                    if exit_after_reload(&script, exit_on_empty) {
+                                        log::info!("Exiting as the script is empty");
+                                        if let Err(e) = tx.send(Event::Exit) {
+                                            log::error!("Failed to send exit event: {}", e);
+                                        }
+                                    }
+                                }
+                                Err(e) => log::error!("Failed to reload script: {}", e),
+                            }
+                        }
+                        Some(Event::Connected(broker)) => {
+                            startup.on_connected(Instant::now());
+                            let is_new = connections
+                                .iter()
+                                .find(|(uri, _)| *uri == broker)
+                                .is_none_or(|(_, state)| {
+                                    connects.is_new(&broker, state.connects())
+                                });
+                            if is_new {
+                                queue_on_connect(&script, &mut pending, &broker);
+                            }
+                        }
+                        Some(Event::Control(Command::Remove { topic, time })) => {
+                            let removed = remove_message(&mut script, &mut pending, &topic, time);
+                            if removed > 0 {
+                                log::info!(
+                                    "Removed {} messages to {} at {}",
+                                    removed,
+                                    topic,
+                                    time.to_rfc3339()
+                                );
+                            }
+                        }
+                        Some(Event::Tick) => {
+                            let dropped = coalesce_ticks(&mut rx, &mut deferred);
+                            if dropped > 0 {
+                                log::debug!("Coalesced {} queued ticks", dropped);
+                            }
+                            if script.has_unsettled_files() {
+                                log::debug!("Reloading files that were being written");
+                                match script.reload() {
+                                    Ok(()) => {
+                                        last_reload = script::now();
+                                        if !script.has_unsettled_files() {
+                                            log_summary(&script);
+                                        }
+                                    }
+                                    Err(e) => log::error!("Failed to reload script: {}", e),
+                                }
+                            }
+                            let mut messages = script.poll();
+                            order_by_priority(&mut messages);
+                            if publish_order == PublishOrder::RoundRobin {
+                                messages = interleave_topics(messages);
+                            }
+                            pending.extend(messages.into_iter().map(Outgoing::from));
+                            if send_interval.is_some() && pending.len() > 1 {
+                                log::debug!("{} messages waiting to be sent", pending.len());
+                            }
+                            // The MQTT tasks wait for every message in flight to be delivered
+                            // before handling the exit, so all messages are delivered first
+                            if once && pending.is_empty() && script.is_exhausted() {
+                                log::info!("All messages have been sent");
+                                finish(&tx, &mut complete_topic, &complete_payload);
+                            }
+                        }
+                        _ => {}
+                    }
                 }
-                Event::ReloadScript => {
-                    if let Err(e) = script.reload() {
-                        log::error!("Failed to reload script: {}", e);
+                _ = sleep_until(next_send.max(startup.open_at().unwrap_or(next_send))),
+                    if !pending.is_empty() && startup.open_at().is_some() => {
+                    let Outgoing { message, broker } = pending.pop_front().unwrap();
+                    if is_stale(&message, script::now()) {
+                        log::warn!("Dropping message that was not sent in time: {}", message);
+                        continue;
+                    }
+                    let description = message.to_string();
+                    let message = match message.resolve_payload(strict_env) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            log::warn!("Skipping message {}: {}", description, e);
+                            continue;
+                        }
+                    };
+                    let message = match apply_condition(message) {
+                        Some(message) => message,
+                        None => {
+                            log::info!(
+                                "Skipping message as its condition is not met: {}",
+                                description
+                            );
+                            continue;
+                        }
+                    };
+                    let message = match payload_transform.apply(message) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            log::warn!("Skipping message {}: {}", description, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = check_payload_size(&message, &payload_size_limits) {
+                        log::warn!("Skipping message {}: {}", message, e);
+                        continue;
+                    }
+                    log::info!("Sending message: {}", message);
+                    if let Err(e) = tx.send(Event::SendMessage(Box::new(message), broker)) {
+                        log::error!("Failed to send send message event: {}", e);
+                    }
+                    messages_sent += 1;
+                    if let Some(interval) = send_interval {
+                        next_send = Instant::now() + interval;
                     }
                 }
-                Event::Tick => {
-                    for message in script.poll() {
-                        log::info!("Sending message: {:?}", message);
-                        if let Err(e) = tx.send(Event::SendMessage(message)) {
-                            log::error!("Failed to send send message event: {}", e);
+                _ = status_interval.tick(), if status_topic.is_some() => {
+                    let status = Status {
+                        uptime: start.elapsed().as_secs(),
+                        messages_sent,
+                        scheduled: script.scheduled_count(),
+                        last_reload: last_reload.to_rfc3339(),
+                        brokers: connections
+                            .iter()
+                            .map(|(broker, state)| BrokerStatus::new(broker, state))
+                            .collect(),
+                        metrics: metrics::snapshot(),
+                    };
+                    match status.to_json() {
+                        Ok(payload) => {
+                            let message = actor_message(status_topic.clone().unwrap(), &payload);
+                            if let Err(e) = tx.send(Event::SendMessage(Box::new(message), None)) {
+                                log::error!("Failed to send status message event: {}", e);
+                            }
                         }
+                        Err(e) => log::error!("Failed to serialize status: {}", e),
                     }
                 }
-                _ => {}
             }
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn priority_order() {
+        let t = script::now();
+
+        let message = |payload: &str, priority| Message {
+            priority,
+            ..Message::new(Timestamp::Absolute(t), "test_topic", payload)
+        };
+
+        let mut messages = vec![
+            message("msg 1", 0),
+            message("msg 2", 5),
+            message("msg 3", 0),
+            message("msg 4", -1),
+            message("msg 5", 5),
+        ];
+        order_by_priority(&mut messages);
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| m.message.clone())
+                .collect::<Vec<_>>(),
+            vec!["msg 2", "msg 5", "msg 1", "msg 3", "msg 4"]
+        );
+    }
+
+    #[test]
+    fn round_robin_order() {
+        let t = script::now();
+
+        let message =
+            |topic: &str, payload: &str| Message::new(Timestamp::Absolute(t), topic, payload);
+
+        let messages = vec![
+            message("a", "a1"),
+            message("a", "a2"),
+            message("a", "a3"),
+            message("b", "b1"),
+            message("a", "a4"),
+            message("c", "c1"),
+            message("c", "c2"),
+            message("b", "b2"),
+        ];
+
+        assert_eq!(
+            interleave_topics(messages)
+                .iter()
+                .map(|m| m.message.clone())
+                .collect::<Vec<_>>(),
+            vec!["a1", "b1", "c1", "a2", "b2", "c2", "a3", "a4"]
+        );
+        assert!(interleave_topics(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn exit_on_empty_reload() {
+        let dir = tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "1h | test_topic | msg").unwrap();
+
+        let mut script = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        script.reload().unwrap();
+        assert!(!exit_after_reload(&script, true));
+
+        fs::remove_file(&path).unwrap();
+        script.reload().unwrap();
+        assert!(!exit_after_reload(&script, false));
+        assert!(exit_after_reload(&script, true));
+    }
+
+    #[test]
+    fn on_connect_once_per_connection() {
+        let dir = tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap();
+        fs::write(
+            dir.path().join("a.txt"),
+            "on-connect | state | on\n1h | a | msg",
+        )
+        .unwrap();
+        let script = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+
+        let mut pending = VecDeque::new();
+        let mut connects = ConnectTracker::default();
+        let mut on_connected = |broker: &str, count| {
+            if connects.is_new(broker, count) {
+                queue_on_connect(&script, &mut pending, broker);
+            }
+        };
+
+        // Connected before the processing task started, with the connected event received after
+        on_connected("tcp://a:1883", 1);
+        on_connected("tcp://a:1883", 1);
+        on_connected("tcp://b:1883", 1);
+        // Reconnected twice before the connected events were handled
+        on_connected("tcp://a:1883", 3);
+        on_connected("tcp://a:1883", 3);
+
+        // The messages are only sent to the broker that connected
+        assert_eq!(
+            pending
+                .iter()
+                .map(|o| (o.message.topic.as_str(), o.broker.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("state", Some("tcp://a:1883")),
+                ("state", Some("tcp://b:1883")),
+                ("state", Some("tcp://a:1883")),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_command() {
+        let dir = tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap();
+        let t = script::now();
+        let soon = t + chrono::Duration::hours(1);
+        let later = t + chrono::Duration::hours(2);
+        fs::write(
+            dir.path().join("a.txt"),
+            format!(
+                "{} | a | msg 1\n{} | a | msg 2",
+                soon.to_rfc3339(),
+                later.to_rfc3339()
+            ),
+        )
+        .unwrap();
+        let mut script = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+
+        // A message that is due, but still waiting to be sent
+        let past = t - chrono::Duration::seconds(1);
+        let mut pending: VecDeque<_> = ["a", "b"]
+            .into_iter()
+            .map(|topic| {
+                Outgoing::from(Message {
+                    timestamp: Timestamp::Absolute(past),
+                    ..actor_message(topic.into(), "msg 0")
+                })
+            })
+            .collect();
+
+        assert_eq!(remove_message(&mut script, &mut pending, "a", later), 1);
+        assert_eq!(script.scheduled_count(), 1);
+        assert_eq!(remove_message(&mut script, &mut pending, "b", soon), 0);
+        assert_eq!(script.scheduled_count(), 1);
+
+        assert_eq!(remove_message(&mut script, &mut pending, "a", past), 1);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message.topic, "b");
+
+        // Messages that have already been sent are not removed
+        assert_eq!(remove_message(&mut script, &mut pending, "a", past), 0);
+    }
+
+    #[tokio::test]
+    async fn ticks_coalesce() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel::<Event>(16);
+
+        // Events that queued up while a slow tick was handled
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::ReloadScript).unwrap();
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::Reconnect).unwrap();
+
+        let mut deferred = VecDeque::new();
+        assert_eq!(coalesce_ticks(&mut rx, &mut deferred), 3);
+        assert_eq!(
+            deferred,
+            VecDeque::from([Event::ReloadScript, Event::Reconnect])
+        );
+
+        // Deferred events are handled before any new ones
+        tx.send(Event::Tick).unwrap();
+        assert_eq!(
+            next_event(&mut rx, &mut deferred).await,
+            Some(Event::ReloadScript)
+        );
+        assert_eq!(
+            next_event(&mut rx, &mut deferred).await,
+            Some(Event::Reconnect)
+        );
+        assert_eq!(next_event(&mut rx, &mut deferred).await, Some(Event::Tick));
+        assert_eq!(coalesce_ticks(&mut rx, &mut deferred), 0);
+    }
+
+    #[test]
+    fn complete_message_sent_once() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel::<Event>(16);
+        let mut complete_topic = Some("batch/status".to_string());
+
+        // Further ticks may be handled before the exit event
+        finish(&tx, &mut complete_topic, "done");
+        finish(&tx, &mut complete_topic, "done");
+
+        match rx.try_recv().unwrap() {
+            Event::SendMessage(message, None) => {
+                assert_eq!(message.topic, "batch/status");
+                assert_eq!(message.message, "done");
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        assert_eq!(rx.try_recv().unwrap(), Event::Exit);
+        assert_eq!(rx.try_recv().unwrap(), Event::Exit);
+        assert!(rx.try_recv().is_err());
+
+        // Without a complete topic only the exit event is sent
+        finish(&tx, &mut None, "done");
+        assert_eq!(rx.try_recv().unwrap(), Event::Exit);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn startup_delay() {
+        let t = Instant::now();
+
+        let mut gate = StartupGate::new(Duration::from_secs(5));
+        assert_eq!(gate.open_at(), None);
+
+        gate.on_connected(t);
+        assert_eq!(gate.open_at(), Some(t + Duration::from_secs(5)));
+
+        // Reconnecting does not delay sending again
+        gate.on_connected(t + Duration::from_secs(60));
+        assert_eq!(gate.open_at(), Some(t + Duration::from_secs(5)));
+
+        let mut gate = StartupGate::new(Duration::ZERO);
+        gate.on_connected(t);
+        assert_eq!(gate.open_at(), Some(t));
+    }
+
+    #[test]
+    fn payload_size_limits() {
+        let limits = ["sensors/=1..8", "sensors/big/=..1024", "alerts=4.."]
+            .iter()
+            .map(|s| parse_payload_size_limit(s).unwrap())
+            .collect::<Vec<_>>();
+
+        let message = |topic: &str, size| {
+            Message::new(
+                Timestamp::Absolute(script::now()),
+                topic,
+                "x".repeat(size).as_str(),
+            )
+        };
+
+        assert!(check_payload_size(&message("sensors/temp", 8), &limits).is_ok());
+        assert!(check_payload_size(&message("sensors/temp", 9), &limits).is_err());
+        assert!(check_payload_size(&message("sensors/temp", 0), &limits).is_err());
+        // The longest matching prefix applies
+        assert!(check_payload_size(&message("sensors/big/image", 1024), &limits).is_ok());
+        assert!(check_payload_size(&message("sensors/big/image", 0), &limits).is_ok());
+        assert!(check_payload_size(&message("sensors/big/image", 1025), &limits).is_err());
+        assert!(check_payload_size(&message("alerts/fire", 3), &limits).is_err());
+        assert!(check_payload_size(&message("alerts/fire", 1_000_000), &limits).is_ok());
+        assert!(check_payload_size(&message("other", 1_000_000), &limits).is_ok());
+
+        assert_eq!(
+            parse_payload_size_limit("a/b=..").unwrap(),
+            PayloadSizeLimit {
+                prefix: "a/b".into(),
+                size: 0..=usize::MAX
+            }
+        );
+        assert!(parse_payload_size_limit("a/b").is_err());
+        assert!(parse_payload_size_limit("a/b=10").is_err());
+        assert!(parse_payload_size_limit("a/b=x..10").is_err());
+        assert!(parse_payload_size_limit("a/b=10..1").is_err());
+    }
+
+    fn transformed(transform: &PayloadTransform, payload: &str) -> Result<Vec<u8>> {
+        let message = Message::new(
+            Timestamp::Absolute(script::now()),
+            "t",
+            payload.parse::<Payload>().unwrap(),
+        );
+        Ok(transform.apply(message)?.message.into())
+    }
+
+    #[test]
+    fn payload_prefix() {
+        let transform = PayloadTransform {
+            prefix: "hex:00ff".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(transformed(&transform, "abc").unwrap(), b"\x00\xffabc");
+        assert_eq!(transformed(&transform, "hex:80").unwrap(), b"\x00\xff\x80");
+        assert_eq!(
+            transformed(&PayloadTransform::default(), "abc").unwrap(),
+            b"abc"
+        );
+    }
+
+    #[test]
+    fn payload_suffix() {
+        let transform = PayloadTransform {
+            prefix: "<".into(),
+            suffix: "hex:0a".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(transformed(&transform, "abc").unwrap(), b"<abc\n");
+        assert_eq!(transformed(&transform, "").unwrap(), b"<\n");
+    }
+
+    #[test]
+    fn payload_wrap_json() {
+        let transform = PayloadTransform {
+            wrap_json: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            transformed(&transform, "21.5").unwrap(),
+            br#"{"value": 21.5}"#
+        );
+        assert_eq!(
+            transformed(&transform, r#"{"b": 1, "a": [true]}"#).unwrap(),
+            br#"{"value": {"b": 1, "a": [true]}}"#
+        );
+        assert_eq!(
+            transformed(&transform, r#"on "off""#).unwrap(),
+            br#"{"value": "on \"off\""}"#
+        );
+        assert!(transformed(&transform, "hex:ff00").is_err());
+
+        // Wrapping happens before the prefix and suffix are added
+        let transform = PayloadTransform {
+            prefix: "[".into(),
+            suffix: "]".into(),
+            wrap_json: true,
+        };
+        assert_eq!(
+            transformed(&transform, "x").unwrap(),
+            br#"[{"value": "x"}]"#
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("enabled");
+
+        let message = |else_topic: Option<&str>| Message {
+            condition: Some(path.clone()),
+            else_topic: else_topic.map(String::from),
+            ..Message::new(Timestamp::Absolute(script::now()), "test_topic", "msg")
+        };
+        let topic = |m: Option<Message>| m.map(|m| m.topic);
+
+        assert!(!condition_met(&path));
+        assert_eq!(topic(apply_condition(message(None))), None);
+        assert_eq!(
+            topic(apply_condition(message(Some("else_topic")))),
+            Some("else_topic".to_string())
+        );
+
+        for contents in ["", "true\n", "TRUE"] {
+            fs::write(&path, contents).unwrap();
+            assert!(condition_met(&path));
+            assert_eq!(
+                topic(apply_condition(message(Some("else_topic")))),
+                Some("test_topic".to_string())
+            );
+        }
+
+        fs::write(&path, "false").unwrap();
+        assert!(!condition_met(&path));
+        assert_eq!(topic(apply_condition(message(None))), None);
+
+        let unconditional = Message {
+            condition: None,
+            ..message(None)
+        };
+        assert_eq!(
+            topic(apply_condition(unconditional)),
+            Some("test_topic".to_string())
+        );
+    }
+
+    #[test]
+    fn stale_messages() {
+        let t = script::now();
+
+        let message = |valid_for| Message {
+            valid_for,
+            ..Message::new(Timestamp::Absolute(t), "test_topic", "msg")
+        };
+
+        assert!(!is_stale(&message(None), t + chrono::Duration::days(1)));
+
+        assert!(!is_stale(&message(Some(10)), t));
+        assert!(!is_stale(
+            &message(Some(10)),
+            t + chrono::Duration::seconds(10)
+        ));
+        assert!(is_stale(
+            &message(Some(10)),
+            t + chrono::Duration::seconds(11)
+        ));
+        assert!(is_stale(
+            &message(Some(0)),
+            t + chrono::Duration::seconds(1)
+        ));
+    }
+}
@@ -0,0 +1,110 @@
+use super::{list::ScheduledMessage, recv_event, script, Event};
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
+use std::{fs::OpenOptions, io::Write, path::Path};
+use tokio::{sync::broadcast::Sender, task::JoinHandle};
+
+/// Appends a message to the record as a line of JSON, with the time it was sent.
+fn record(
+    w: &mut impl Write,
+    msg: &script::Message,
+    time: DateTime<FixedOffset>,
+    qos: i32,
+) -> Result<()> {
+    let line = serde_json::to_string(&ScheduledMessage::new(msg, time, qos))?;
+    writeln!(w, "{}", line)?;
+    Ok(())
+}
+
+/// Records every message that is sent to a file, e.g. so that tests can check what was sent
+/// without a broker.
+/// The file is appended to, with the same fields as `--dump-json` and the QoS used if a message
+/// does not set its own.
+pub(crate) fn run(tx: Sender<Event>, path: &Path, qos: i32) -> Result<JoinHandle<()>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut rx = tx.subscribe();
+
+    Ok(tokio::spawn(async move {
+        while let Some(event) = recv_event(&mut rx, "Record").await {
+            match event {
+                Event::Exit => {
+                    log::debug!("Task exit");
+                    return;
+                }
+                Event::SendMessage(msg, _) => {
+                    if let Err(e) = record(&mut file, &msg, script::now(), qos) {
+                        log::error!("Failed to record message to {}: {}", msg.topic, e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::{Script, ScriptOptions};
+    use std::fs;
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn records_sent_messages() {
+        let dir = tempfile::Builder::new().prefix("test").tempdir().unwrap();
+        fs::write(
+            dir.path().join("script.txt"),
+            "1h | a | msg 1\n1h | b | msg 2 | true\n1h | c | msg 3 | false | | 0 | | | | 2",
+        )
+        .unwrap();
+        let script = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let scheduled = script.upcoming(script::now() + chrono::Duration::hours(4), 1);
+        assert_eq!(scheduled.len(), 3);
+
+        let path = dir.path().join("record.jsonl");
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), &path, 1).unwrap();
+
+        let start = script::now();
+        for m in &scheduled {
+            tx.send(Event::SendMessage(Box::new(m.clone()), None))
+                .unwrap();
+        }
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::Exit).unwrap();
+        task.await.unwrap();
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), scheduled.len());
+
+        for (record, m) in records.iter().zip(&scheduled) {
+            let time = DateTime::parse_from_rfc3339(record["time"].as_str().unwrap()).unwrap();
+            assert!(time >= start && time <= script::now());
+            assert_eq!(record["topic"], m.topic);
+            assert_eq!(record["payload"], m.message.to_string_lossy());
+            assert_eq!(record["qos"], m.qos.unwrap_or(1));
+            assert_eq!(record["retain"], m.retain);
+        }
+        assert_eq!(records[1]["retain"], true);
+        assert_eq!(records[2]["qos"], 2);
+    }
+
+    #[tokio::test]
+    async fn stops_when_channel_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let task = run(tx.clone(), &dir.path().join("record.jsonl"), 1).unwrap();
+
+        // Dropping every sender without an exit event stops the task rather than leaving it
+        // waiting
+        drop(tx);
+        tokio::time::timeout(std::time::Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
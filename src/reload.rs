@@ -0,0 +1,133 @@
+use super::{recv_event, script, Event};
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::broadcast::Sender, task::JoinHandle};
+
+/// Interval at which a script fetched from a URL is fetched again, unless another one is given.
+pub(crate) const URL_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Modification time and size of each script file, used to detect changes without relying on
+/// filesystem events.
+#[derive(Debug, Default, PartialEq)]
+struct Snapshot(BTreeMap<PathBuf, (Option<SystemTime>, u64)>);
+
+impl Snapshot {
+    fn new(dir: &Path, globs: &[String], ignore: &[String]) -> Result<Self> {
+        Ok(Snapshot(
+            script::find_script_files(dir, globs, ignore)?
+                .into_iter()
+                .filter_map(|path| {
+                    let metadata = fs::metadata(&path).ok()?;
+                    Some((path, (metadata.modified().ok(), metadata.len())))
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Periodically reloads the script if any script file has been added, changed or removed.
+/// This is an alternative to watching for filesystem events, which does not work on some network
+/// filesystems. A script fetched from a URL is reloaded every interval, the reload checks whether
+/// it has changed.
+pub(crate) fn run(
+    tx: Sender<Event>,
+    dir: &Path,
+    globs: &[String],
+    ignore: &[String],
+    interval: Duration,
+) -> JoinHandle<()> {
+    let mut rx = tx.subscribe();
+    let dir = dir.to_path_buf();
+    let globs = globs.to_vec();
+    let ignore = ignore.to_vec();
+    let is_url = script::script_url(&dir).is_some();
+
+    tokio::spawn(async move {
+        let mut snapshot = Snapshot::new(&dir, &globs, &ignore).unwrap_or_default();
+
+        loop {
+            tokio::select! {
+                event = recv_event(&mut rx, "Reload") => {
+                    if matches!(event, Some(Event::Exit) | None) {
+                        log::debug!("Task exit");
+                        return;
+                    }
+                }
+                _ = tokio::time::sleep(interval), if is_url => {
+                    if let Err(e) = tx.send(Event::ReloadScript) {
+                        log::error!("Failed to send reload trigger: {}", e);
+                    }
+                }
+                _ = tokio::time::sleep(interval), if !is_url => {
+                    match Snapshot::new(&dir, &globs, &ignore) {
+                        Ok(new_snapshot) => {
+                            if new_snapshot != snapshot {
+                                log::debug!("Script files have changed");
+                                snapshot = new_snapshot;
+                                if let Err(e) = tx.send(Event::ReloadScript) {
+                                    log::error!("Failed to send reload trigger: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed to check script files for changes: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_changes() {
+        let dir = tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "0 | test_topic | msg").unwrap();
+
+        let globs = vec!["**/*.txt".to_string()];
+        let ignore = vec!["*~".to_string()];
+
+        let snapshot = Snapshot::new(dir.path(), &globs, &ignore).unwrap();
+        assert_eq!(
+            Snapshot::new(dir.path(), &globs, &ignore).unwrap(),
+            snapshot
+        );
+
+        // Ignored files do not count as changes
+        fs::write(dir.path().join("notes.md"), "notes").unwrap();
+        fs::write(dir.path().join("a.txt~"), "0 | test_topic | msg").unwrap();
+        assert_eq!(
+            Snapshot::new(dir.path(), &globs, &ignore).unwrap(),
+            snapshot
+        );
+
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let modified = Snapshot::new(dir.path(), &globs, &ignore).unwrap();
+        assert_ne!(modified, snapshot);
+
+        fs::write(dir.path().join("b.txt"), "0 | test_topic | msg").unwrap();
+        let added = Snapshot::new(dir.path(), &globs, &ignore).unwrap();
+        assert_ne!(added, modified);
+
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        assert_eq!(
+            Snapshot::new(dir.path(), &globs, &ignore).unwrap(),
+            modified
+        );
+    }
+}
@@ -1,35 +1,354 @@
+use super::warnings;
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Duration, FixedOffset, Local};
-use csv::{ReaderBuilder, Trim};
-use glob::glob;
+use base64::Engine;
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, Offset,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use flate2::read::GzDecoder;
+use glob::{glob_with, MatchOptions, Pattern};
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer,
 };
 use std::{
     cmp::Ordering,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BTreeSet, HashMap, HashSet,
+    },
     fmt,
-    fs::File,
-    io::{BufReader, Read},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, Read},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
     time::SystemTime,
 };
 
-fn now() -> DateTime<FixedOffset> {
+pub fn now() -> DateTime<FixedOffset> {
     DateTime::from(DateTime::<Local>::from(SystemTime::now()))
 }
 
+/// Parses a duration given in seconds, optionally suffixed with a unit ("s", "m", "h" or "d").
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 60 * 60 * 24),
+        _ => (s, 1),
+    };
+
+    let value: i64 = value.parse()?;
+    value
+        .checked_mul(multiplier)
+        .and_then(Duration::try_seconds)
+        .ok_or_else(|| anyhow!("Duration \"{}\" is too large", s))
+}
+
+/// Formats accepted for absolute timestamps without a timezone offset.
+const NAIVE_TIMESTAMP_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// Resolves a date and time without an offset in the given timezone.
+fn resolve_naive<Tz: TimeZone>(t: NaiveDateTime, tz: &Tz) -> Result<DateTime<FixedOffset>> {
+    match t.and_local_timezone(tz.clone()) {
+        LocalResult::Single(t) => Ok(t.with_timezone(&t.offset().fix())),
+        LocalResult::Ambiguous(earliest, _) => {
+            log::warn!(
+                "Time \"{}\" is ambiguous in the local timezone, using the earliest",
+                t
+            );
+            Ok(earliest.with_timezone(&earliest.offset().fix()))
+        }
+        LocalResult::None => Err(anyhow!(
+            "Time \"{}\" does not exist in the local timezone",
+            t
+        )),
+    }
+}
+
+/// The start of a day in the given timezone.
+/// This is midnight, unless midnight is skipped by a DST change, in which case it is the first hour
+/// of the day that exists.
+fn start_of_day<Tz: TimeZone>(day: NaiveDate, tz: &Tz) -> Option<DateTime<FixedOffset>> {
+    (0..24).find_map(
+        |hour| match day.and_hms_opt(hour, 0, 0)?.and_local_timezone(tz.clone()) {
+            LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => {
+                Some(t.with_timezone(&t.offset().fix()))
+            }
+            LocalResult::None => None,
+        },
+    )
+}
+
+/// Timezone in which times without an offset, daily entries and looping scripts are interpreted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Zone {
+    /// The local timezone of the system
+    #[default]
+    Local,
+    /// An IANA timezone, e.g. "Europe/London"
+    Named(Tz),
+}
+
+impl Zone {
+    fn resolve_naive(&self, t: NaiveDateTime) -> Result<DateTime<FixedOffset>> {
+        match self {
+            Zone::Local => resolve_naive(t, &Local),
+            Zone::Named(tz) => resolve_naive(t, tz),
+        }
+    }
+
+    /// The day on which the given time falls.
+    fn date(&self, t: DateTime<FixedOffset>) -> NaiveDate {
+        match self {
+            Zone::Local => t.with_timezone(&Local).date_naive(),
+            Zone::Named(tz) => t.with_timezone(tz).date_naive(),
+        }
+    }
+
+    fn start_of_day(&self, day: NaiveDate) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Zone::Local => start_of_day(day, &Local),
+            Zone::Named(tz) => start_of_day(day, tz),
+        }
+    }
+
+    fn daily_occurrences(
+        &self,
+        offset: Duration,
+        start: DateTime<FixedOffset>,
+        end: DateTime<FixedOffset>,
+    ) -> Vec<DateTime<FixedOffset>> {
+        match self {
+            Zone::Local => daily_occurrences(offset, start, end, &Local),
+            Zone::Named(tz) => daily_occurrences(offset, start, end, tz),
+        }
+    }
+}
+
+/// Parses a timezone, either "local" or an IANA timezone name (e.g. "Europe/London").
+pub fn parse_zone(s: &str) -> Result<Zone> {
+    if s.eq_ignore_ascii_case("local") {
+        return Ok(Zone::Local);
+    }
+    s.parse()
+        .map(Zone::Named)
+        .map_err(|e| anyhow!("Invalid timezone \"{}\": {}", s, e))
+}
+
+/// The times in the window (start, end] at which a daily entry with the given offset from midnight
+/// fires.
+/// Each occurrence is computed from the actual start of its day, so DST changes do not shift the
+/// entry onto the wrong day.
+fn daily_occurrences<Tz: TimeZone>(
+    offset: Duration,
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    tz: &Tz,
+) -> Vec<DateTime<FixedOffset>> {
+    let mut occurrences = Vec::new();
+
+    let mut day = start.with_timezone(tz).date_naive();
+    let last_day = end.with_timezone(tz).date_naive();
+
+    while day <= last_day {
+        if let Some(t) = start_of_day(day, tz).map(|midnight| midnight + offset) {
+            if t > start && t <= end {
+                occurrences.push(t);
+            }
+        }
+        day += Duration::days(1);
+    }
+
+    occurrences
+}
+
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum Timestamp {
+pub enum Timestamp {
     Absolute(DateTime<FixedOffset>),
     Relative(Duration),
+    /// Relative to the message tagged with the named anchor
+    Anchored {
+        anchor: String,
+        offset: Duration,
+    },
+    /// Repeats every interval, at most count times if given, anchored at the time the entry is
+    /// loaded
+    Every {
+        interval: Duration,
+        count: Option<u32>,
+    },
+    /// A resolved repeating entry, tracking the next time it is due to fire and how many times it
+    /// may still fire
+    Recurring {
+        next: DateTime<FixedOffset>,
+        interval: Duration,
+        remaining: Option<u32>,
+    },
+    /// Repeats every day at the given offset from local midnight
+    Daily(Duration),
+    /// Sent whenever a connection to a broker is established, instead of at a scheduled time
+    OnConnect,
+    /// The given time before the configured deadline, e.g. for countdowns
+    Countdown(Duration),
+    /// The given time after the script was last loaded or reloaded, which is sent again after
+    /// every reload
+    AfterReload(Duration),
 }
 
-impl FromStr for Timestamp {
-    type Err = anyhow::Error;
+impl Timestamp {
+    /// The time at which the entry is next due to fire, if it has been resolved.
+    pub fn scheduled_time(&self) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Timestamp::Absolute(t) => Some(*t),
+            Timestamp::Recurring { next, .. } => Some(*next),
+            _ => None,
+        }
+    }
+
+    fn parse_anchored(s: &str) -> Result<Self> {
+        let (anchor, offset) = match s.find(['+', '-']) {
+            Some(i) => {
+                let (anchor, offset) = s.split_at(i);
+                let offset = parse_duration(offset.strip_prefix('+').unwrap_or(offset))?;
+                (anchor, offset)
+            }
+            None => (s, Duration::zero()),
+        };
+
+        if anchor.is_empty() {
+            return Err(anyhow!("Anchor name is empty"));
+        }
+
+        Ok(Timestamp::Anchored {
+            anchor: anchor.to_string(),
+            offset,
+        })
+    }
+
+    fn parse_every(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+
+        let interval = parse_duration(parts.next().unwrap_or_default())?;
+        if interval <= Duration::zero() {
+            return Err(anyhow!("Repeat interval must be greater than zero"));
+        }
+
+        let count = match parts.next() {
+            Some(count) => Some(count.parse()?),
+            None => None,
+        };
+
+        Ok(Timestamp::Every { interval, count })
+    }
+
+    fn parse_daily(s: &str) -> Result<Self> {
+        let offset = match s.strip_prefix('+') {
+            Some(offset) => parse_duration(offset)?,
+            None if s.is_empty() => Duration::zero(),
+            None => return Err(anyhow!("Expected an offset of the form \"+<seconds>\"")),
+        };
+
+        if offset < Duration::zero() || offset >= Duration::days(1) {
+            return Err(anyhow!("Offset from midnight must be less than one day"));
+        }
+
+        Ok(Timestamp::Daily(offset))
+    }
+
+    /// Parses a Unix timestamp, the number of units since the epoch, e.g. seconds or milliseconds.
+    fn parse_epoch(s: &str, units_per_second: i64) -> Result<Self> {
+        let units: i64 = s.parse()?;
+        let seconds = units.div_euclid(units_per_second);
+        let nanos = units.rem_euclid(units_per_second) * (1_000_000_000 / units_per_second);
+        match Utc.timestamp_opt(seconds, nanos as u32) {
+            LocalResult::Single(t) => Ok(Timestamp::Absolute(t.into())),
+            _ => Err(anyhow!("Time is out of range")),
+        }
+    }
+
+    fn parse_countdown(s: &str) -> Result<Self> {
+        let offset = match s.strip_prefix('-') {
+            Some(offset) => parse_duration(offset)?,
+            None if s.is_empty() => Duration::zero(),
+            None => return Err(anyhow!("Expected an offset of the form \"-<seconds>\"")),
+        };
+
+        if offset < Duration::zero() {
+            return Err(anyhow!("Offset before the deadline must not be negative"));
+        }
+
+        Ok(Timestamp::Countdown(offset))
+    }
+
+    fn parse_after_reload(s: &str) -> Result<Self> {
+        let offset = match s.strip_prefix('+') {
+            Some(offset) => parse_duration(offset)?,
+            None if s.is_empty() => Duration::zero(),
+            None => return Err(anyhow!("Expected an offset of the form \"+<seconds>\"")),
+        };
+
+        if offset < Duration::zero() {
+            return Err(anyhow!("Offset after the reload must not be negative"));
+        }
+
+        Ok(Timestamp::AfterReload(offset))
+    }
+}
+
+/// Character that starts a comment line in script files.
+const COMMENT: u8 = b'#';
+
+/// Timestamp of messages that are sent whenever a connection to a broker is established.
+const ON_CONNECT: &str = "on-connect";
+
+impl Timestamp {
+    /// Parses a timestamp, interpreting absolute times without an offset in the given timezone.
+    pub fn parse(s: &str, zone: Zone) -> Result<Self> {
+        if let Some(anchored) = s.strip_prefix('@') {
+            return Timestamp::parse_anchored(anchored)
+                .map_err(|e| anyhow!("Invalid anchored timestamp \"{}\": {}", s, e));
+        }
+
+        if let Some(every) = s.strip_prefix("every:") {
+            return Timestamp::parse_every(every)
+                .map_err(|e| anyhow!("Invalid repeating timestamp \"{}\": {}", s, e));
+        }
+
+        if s == ON_CONNECT {
+            return Ok(Timestamp::OnConnect);
+        }
+
+        if let Some(daily) = s.strip_prefix("midnight") {
+            return Timestamp::parse_daily(daily)
+                .map_err(|e| anyhow!("Invalid daily timestamp \"{}\": {}", s, e));
+        }
+
+        if let Some(epoch) = s.strip_prefix("epoch:") {
+            return Timestamp::parse_epoch(epoch, 1)
+                .map_err(|e| anyhow!("Invalid epoch timestamp \"{}\": {}", s, e));
+        }
+
+        if let Some(epoch) = s.strip_prefix("epochms:") {
+            return Timestamp::parse_epoch(epoch, 1000)
+                .map_err(|e| anyhow!("Invalid epoch timestamp \"{}\": {}", s, e));
+        }
+
+        if let Some(countdown) = s.strip_prefix("deadline") {
+            return Timestamp::parse_countdown(countdown)
+                .map_err(|e| anyhow!("Invalid countdown timestamp \"{}\": {}", s, e));
+        }
+
+        if let Some(after_reload) = s.strip_prefix("reload") {
+            return Timestamp::parse_after_reload(after_reload)
+                .map_err(|e| anyhow!("Invalid reload timestamp \"{}\": {}", s, e));
+        }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match DateTime::parse_from_rfc2822(s) {
             Ok(t) => {
                 return Ok(Timestamp::Absolute(t));
@@ -48,12 +367,28 @@ impl FromStr for Timestamp {
             }
         }
 
-        match s.parse() {
+        for format in NAIVE_TIMESTAMP_FORMATS {
+            match NaiveDateTime::parse_from_str(s, format) {
+                Ok(t) => {
+                    return zone.resolve_naive(t).map(Timestamp::Absolute);
+                }
+                Err(e) => {
+                    log::debug!(
+                        "Failed to parse \"{}\" as local timestamp ({}): {}",
+                        s,
+                        format,
+                        e
+                    );
+                }
+            }
+        }
+
+        match parse_duration(s) {
             Ok(t) => {
-                return Ok(Timestamp::Relative(Duration::seconds(t)));
+                return Ok(Timestamp::Relative(t));
             }
             Err(e) => {
-                log::debug!("Failed to parse \"{}\" as offset in seconds: {}", s, e);
+                log::debug!("Failed to parse \"{}\" as relative offset: {}", s, e);
             }
         }
 
@@ -61,6 +396,53 @@ impl FromStr for Timestamp {
     }
 }
 
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Timestamp::parse(s, Zone::default())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Absolute(t) => write!(f, "{}", t.to_rfc3339()),
+            Timestamp::Relative(offset) => write!(f, "+{}s", offset.num_seconds()),
+            Timestamp::Anchored { anchor, offset } => {
+                write!(f, "@{}{:+}s", anchor, offset.num_seconds())
+            }
+            Timestamp::Every { interval, count } => {
+                write!(f, "every {}s", interval.num_seconds())?;
+                match count {
+                    Some(count) => write!(f, ", {} times", count),
+                    None => Ok(()),
+                }
+            }
+            Timestamp::Recurring {
+                next,
+                interval,
+                remaining,
+            } => {
+                write!(
+                    f,
+                    "{} (every {}s",
+                    next.to_rfc3339(),
+                    interval.num_seconds()
+                )?;
+                match remaining {
+                    Some(remaining) => write!(f, ", {} remaining)", remaining),
+                    None => write!(f, ")"),
+                }
+            }
+            Timestamp::Daily(offset) => write!(f, "daily at midnight+{}s", offset.num_seconds()),
+            Timestamp::OnConnect => write!(f, "on connect"),
+            Timestamp::Countdown(offset) => write!(f, "deadline-{}s", offset.num_seconds()),
+            Timestamp::AfterReload(offset) => write!(f, "reload+{}s", offset.num_seconds()),
+        }
+    }
+}
+
 struct TimestampVisitor;
 
 impl<'de> Visitor<'de> for TimestampVisitor {
@@ -68,7 +450,7 @@ impl<'de> Visitor<'de> for TimestampVisitor {
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         formatter.write_str(
-            "an absolute timestamp in RFC2822 or RFC3339 format or a relative time in seconds",
+            "an absolute timestamp in RFC2822, RFC3339 or local time format, a relative time, a repeat interval or an offset from midnight",
         )
     }
 
@@ -92,240 +474,4397 @@ impl<'de> Deserialize<'de> for Timestamp {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-pub(crate) struct Message {
-    pub timestamp: Timestamp,
-    pub topic: String,
-    pub message: String,
-}
-
-fn load_messages<R: Read>(delimiter: u8, reader: R) -> Vec<Message> {
-    let mut offset_time = now();
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow!("Hex string has an odd number of digits"));
+    }
 
-    ReaderBuilder::new()
-        .has_headers(false)
-        .trim(Trim::All)
-        .delimiter(delimiter)
-        .from_reader(reader)
-        .deserialize()
-        .filter_map(|m| match m {
-            Ok(m) => Some(m),
-            Err(e) => {
-                log::warn!("Failed to parse message script: {}", e);
-                None
-            }
-        })
-        .map(|mut m: Message| match m.timestamp {
-            Timestamp::Absolute(msg_time) => {
-                offset_time = msg_time;
-                m
-            }
-            Timestamp::Relative(msg_time) => {
-                let msg_time = offset_time + msg_time;
-                m.timestamp = Timestamp::Absolute(msg_time);
-                offset_time = msg_time;
-                m
-            }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex digits at position {}", i))
         })
         .collect()
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct Script {
-    source_dir: PathBuf,
-    source_file_delimiter: u8,
+/// A message payload.
+/// Payloads prefixed with "base64:" or "hex:" are decoded to raw bytes, anything else is used
+/// verbatim.
+#[derive(Clone, Default, PartialEq)]
+pub struct Payload(Vec<u8>);
 
-    messages: Vec<Message>,
+impl Payload {
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
 
-    last_poll_time: DateTime<FixedOffset>,
-}
+    /// Size of the payload in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-impl Script {
-    pub(crate) fn new(dir: &Path, delimiter: u8) -> Result<Self> {
-        let mut s = Script {
-            source_dir: dir.to_path_buf(),
-            source_file_delimiter: delimiter,
-            messages: Vec::new(),
-            last_poll_time: now(),
-        };
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 
-        if let Err(e) = s.reload() {
-            log::error!("Initial script loading failed: {}", e);
-        }
+    /// The payload with the bytes of the prefix before it and those of the suffix after it.
+    pub fn surround(&self, prefix: &Payload, suffix: &Payload) -> Payload {
+        Payload([prefix.0.as_slice(), &self.0, &suffix.0].concat())
+    }
 
-        Ok(s)
+    /// Wraps the payload in a JSON object, as its "value" field.
+    /// A payload that is valid JSON is embedded as is, other text is embedded as a string. Binary
+    /// payloads can not be wrapped.
+    pub fn wrap_json(&self) -> Result<Payload> {
+        let text = std::str::from_utf8(&self.0)
+            .map_err(|_| anyhow!("Binary payloads can not be wrapped in JSON"))?;
+        let value = if serde_json::from_str::<de::IgnoredAny>(text).is_ok() {
+            text.trim().to_string()
+        } else {
+            serde_json::to_string(text)?
+        };
+        Ok(Payload(format!("{{\"value\": {}}}", value).into_bytes()))
     }
+}
 
-    pub(crate) fn reload(&mut self) -> Result<()> {
-        log::debug!("Building script from \"{}\"", &self.source_dir.display());
+impl From<&str> for Payload {
+    fn from(s: &str) -> Self {
+        Payload(s.as_bytes().to_vec())
+    }
+}
 
-        self.messages = glob(&format!("{}/**/*.txt", self.source_dir.display()))?
-            .filter_map(|path| match path {
-                Ok(path) => {
-                    if path.iter().any(|i| {
-                        if let Some(i) = i.to_str() {
-                            i.starts_with('.')
-                        } else {
-                            false
-                        }
-                    }) {
-                        log::info!("Discarding path: {:?}", path);
-                        None
-                    } else {
-                        Some(path)
-                    }
-                }
-                Err(_) => None,
-            })
-            .filter_map(|path| {
-                log::info!("Loading file {:?}", path);
-                Some(load_messages(
-                    self.source_file_delimiter,
-                    BufReader::new(File::open(path).ok()?),
-                ))
-            })
-            .flatten()
-            .collect();
+impl From<Payload> for Vec<u8> {
+    fn from(payload: Payload) -> Self {
+        payload.0
+    }
+}
 
-        // This sort is not strictly necessary, the core functionality will work mostly the same
-        // without it. It is just here to provide logical ordering for logging and ensures messages
-        // falling within the same poll() time window are delivered in timestamp order.
-        self.messages.sort_by(|a, b| {
-            if let Timestamp::Absolute(a) = a.timestamp {
-                if let Timestamp::Absolute(b) = b.timestamp {
-                    a.cmp(&b)
-                } else {
-                    Ordering::Equal
-                }
-            } else {
-                Ordering::Equal
-            }
-        });
+impl PartialEq<&str> for Payload {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
 
-        log::info!("Loaded messages:");
-        for m in &self.messages {
-            log::info!("{:?}", m);
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => write!(f, "{:?}", s),
+            Err(_) => write!(f, "{:02x?}", self.0),
         }
+    }
+}
 
-        Ok(())
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => write!(f, "{:02x?}", self.0),
+        }
     }
+}
 
-    pub(crate) fn poll(&mut self) -> Vec<Message> {
-        let start = self.last_poll_time;
-        let end = now();
+impl FromStr for Payload {
+    type Err = anyhow::Error;
 
-        let msgs = self
-            .messages
-            .iter()
-            .filter_map(|m| {
-                if let Timestamp::Absolute(t) = m.timestamp {
-                    if t > start && t <= end {
-                        Some(m.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(encoded) = s.strip_prefix("base64:") {
+            Ok(Payload(
+                base64::engine::general_purpose::STANDARD.decode(encoded)?,
+            ))
+        } else if let Some(encoded) = s.strip_prefix("hex:") {
+            Ok(Payload(decode_hex(encoded)?))
+        } else {
+            Ok(Payload::from(s))
+        }
+    }
+}
 
-        self.last_poll_time = end;
+/// Prefix of payloads that are read from a file when the message is sent.
+const FILE_PAYLOAD_PREFIX: &str = "file:";
 
-        msgs
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Payload;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a payload, optionally prefixed with \"base64:\" or \"hex:\"")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        match Payload::from_str(value) {
+            Ok(p) => Ok(p),
+            Err(e) => Err(de::Error::custom(e)),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use chrono::TimeZone;
-    use std::io::Cursor;
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D>(deserializer: D) -> Result<Payload, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PayloadVisitor)
+    }
+}
 
-    #[test]
-    fn timestamp_parse_absolute() {
-        assert_eq!(
-            Timestamp::from_str("2022-03-28T10:23:33+00:00").unwrap(),
-            Timestamp::Absolute(FixedOffset::east(0).ymd(2022, 3, 28).and_hms(10, 23, 33))
-        );
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Message {
+    pub timestamp: Timestamp,
+    pub topic: String,
+    pub message: Payload,
+    #[serde(default)]
+    pub retain: bool,
+    /// Message expiry interval in seconds, only supported with MQTT v5
+    #[serde(default)]
+    pub expiry: Option<u32>,
+    /// Messages with a higher priority are sent first when several are due at the same time
+    #[serde(default)]
+    pub priority: i32,
+    /// Time in seconds after the scheduled time within which the message must be sent, after
+    /// which it is dropped instead of being sent late
+    #[serde(default)]
+    pub valid_for: Option<u32>,
+    /// MQTT content type of the payload, only supported with MQTT v5
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// MQTT response topic for request/response messages, only supported with MQTT v5
+    #[serde(default)]
+    pub response_topic: Option<String>,
+    /// MQTT QoS of the message, the configured QoS is used if not set
+    #[serde(default, deserialize_with = "deserialize_qos")]
+    pub qos: Option<i32>,
+    /// File that must exist, and be empty or contain "true", when the message is due for it to be
+    /// sent, given as "condition:[path]"
+    #[serde(default, deserialize_with = "deserialize_condition")]
+    pub condition: Option<PathBuf>,
+    /// Topic the message is sent to instead if its condition is not met, it is skipped if not set
+    #[serde(default)]
+    pub else_topic: Option<String>,
+    /// File the payload is read from when the message is sent, instead of the payload given in
+    /// the script
+    #[serde(skip)]
+    pub payload_file: Option<PathBuf>,
+}
 
-        assert_eq!(
-            Timestamp::from_str("Mon, 28 Mar 2022 10:23:33 GMT").unwrap(),
-            Timestamp::Absolute(FixedOffset::east(0).ymd(2022, 3, 28).and_hms(10, 23, 33))
-        );
+/// Deserializes an optional MQTT QoS, which must be 0, 1 or 2.
+fn deserialize_qos<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<i32>::deserialize(deserializer)? {
+        Some(qos) if !(0..=2).contains(&qos) => Err(de::Error::custom(format!(
+            "invalid QoS {}, must be 0, 1 or 2",
+            qos
+        ))),
+        qos => Ok(qos),
     }
+}
 
-    #[test]
-    fn timestamp_parse_relative() {
-        assert_eq!(
-            Timestamp::from_str("25").unwrap(),
-            Timestamp::Relative(Duration::seconds(25))
-        );
+/// Prefix of the condition of a message, followed by the path of the condition file.
+const CONDITION_PREFIX: &str = "condition:";
+
+/// Deserializes an optional condition of the form "condition:[path]" to its path.
+fn deserialize_condition<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(condition) => match condition.strip_prefix(CONDITION_PREFIX) {
+            Some(path) if !path.trim().is_empty() => Ok(Some(PathBuf::from(path.trim()))),
+            _ => Err(de::Error::custom(format!(
+                "invalid condition \"{}\", expected the form \"{}[path]\"",
+                condition, CONDITION_PREFIX
+            ))),
+        },
+        None => Ok(None),
     }
+}
 
-    #[test]
-    fn messages_with_absolute_and_relative() {
-        let data = r##"
-Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | msg 1
-10                            | root/user-2 | msg 2
-20                            | root/user-1 | msg 3
-"##;
-        let c = Cursor::new(data);
-        let msgs = load_messages(b'|', c);
-        assert_eq!(msgs.len(), 3);
+impl Message {
+    /// A message with the given timestamp, topic and payload, and defaults for everything else.
+    pub fn new(
+        timestamp: Timestamp,
+        topic: impl Into<String>,
+        message: impl Into<Payload>,
+    ) -> Self {
+        Message {
+            timestamp,
+            topic: topic.into(),
+            message: message.into(),
+            retain: false,
+            expiry: None,
+            priority: 0,
+            valid_for: None,
+            content_type: None,
+            response_topic: None,
+            qos: None,
+            condition: None,
+            else_topic: None,
+            payload_file: None,
+        }
     }
 
-    #[test]
-    fn messages_with_only_relative() {
-        let data = r##"
- 5 | root/user-1 | msg 1
-10 | root/user-2 | msg 2
-20 | root/user-1 | msg 3
-"##;
-        let c = Cursor::new(data);
-        let msgs = load_messages(b'|', c);
-        assert_eq!(msgs.len(), 3);
+    /// Reads the payload of a message with a file payload, replacing "${VAR}" in it with the value
+    /// of the environment variable VAR.
+    /// This is done when the message is sent, so that changes to the file are picked up.
+    pub fn resolve_payload(mut self, strict_env: bool) -> Result<Self> {
+        if let Some(path) = self.payload_file.take() {
+            let contents =
+                fs::read(&path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+            self.message = match String::from_utf8(contents) {
+                Ok(contents) => Payload::from(interpolate_env(&contents, strict_env)?.as_str()),
+                Err(e) => Payload(e.into_bytes()),
+            };
+        }
+        Ok(self)
     }
 
-    #[test]
-    fn script_poll() {
-        let data = r##"
-1 | test_topic | msg 1
-1 | test_topic | msg 2
+    /// Orders messages by scheduled time, then by topic and payload, so that messages that are
+    /// due at the same time are always in the same order, whatever order the files were found in.
+    /// Sorting with this is stable, so identical messages keep their order of file and line.
+    pub fn schedule_cmp(&self, other: &Self) -> Ordering {
+        self.timestamp
+            .scheduled_time()
+            .cmp(&other.timestamp.scheduled_time())
+            .then_with(|| self.topic.cmp(&other.topic))
+            .then_with(|| self.message.0.cmp(&other.message.0))
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.payload_file {
+            Some(path) => write!(
+                f,
+                "[{}] {} = {}{}",
+                self.timestamp,
+                self.topic,
+                FILE_PAYLOAD_PREFIX,
+                path.display()
+            )?,
+            None => write!(f, "[{}] {} = {}", self.timestamp, self.topic, self.message)?,
+        }
+        if self.retain {
+            write!(f, " (retained)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits an anchor name from the timestamp field of a record, if it is of the form
+/// "[name]=[timestamp]".
+fn take_anchor(record: &mut StringRecord) -> Option<String> {
+    let (anchor, timestamp) = record.get(0)?.split_once('=')?;
+    let (anchor, timestamp) = (anchor.trim().to_string(), timestamp.trim().to_string());
+
+    *record = std::iter::once(timestamp.as_str())
+        .chain(record.iter().skip(1))
+        .collect();
+
+    Some(anchor)
+}
+
+#[derive(Clone, Copy)]
+enum Resolution {
+    Pending,
+    InProgress,
+    Resolved(DateTime<FixedOffset>),
+    Failed,
+}
+
+impl From<&Result<DateTime<FixedOffset>>> for Resolution {
+    fn from(result: &Result<DateTime<FixedOffset>>) -> Self {
+        match result {
+            Ok(t) => Resolution::Resolved(*t),
+            Err(_) => Resolution::Failed,
+        }
+    }
+}
+
+/// How the time of a message is derived, either directly or from the time of another message.
+enum Derivation {
+    Time(Result<DateTime<FixedOffset>>),
+    /// The time of the message with the index, plus the offset
+    From(usize, Duration),
+}
+
+/// Resolves the timestamps of the messages in a single script file to absolute times.
+struct Resolver<'a> {
+    messages: &'a [Message],
+    anchors: HashMap<String, usize>,
+    load_time: DateTime<FixedOffset>,
+    deadline: Option<DateTime<FixedOffset>>,
+    relative_mode: RelativeMode,
+    state: Vec<Resolution>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(
+        messages: &'a [Message],
+        anchors: HashMap<String, usize>,
+        load_time: DateTime<FixedOffset>,
+        deadline: Option<DateTime<FixedOffset>>,
+        relative_mode: RelativeMode,
+    ) -> Self {
+        Self {
+            messages,
+            anchors,
+            load_time,
+            deadline,
+            relative_mode,
+            state: vec![Resolution::Pending; messages.len()],
+        }
+    }
+
+    /// The time relative timestamps are offset from, i.e. the time of the previous message or the
+    /// load time for the first message, or always the load time if they are independent.
+    /// Repeating, daily, on connect and reload messages do not affect the time of the messages
+    /// that follow them.
+    fn base(&self, i: usize, offset: Duration) -> Derivation {
+        if self.relative_mode == RelativeMode::Independent {
+            return Derivation::Time(Ok(self.load_time + offset));
+        }
+
+        match (0..i).rev().find(|&j| {
+            !matches!(
+                self.messages[j].timestamp,
+                Timestamp::Every { .. }
+                    | Timestamp::Daily(_)
+                    | Timestamp::OnConnect
+                    | Timestamp::AfterReload(_)
+            )
+        }) {
+            Some(j) => Derivation::From(j, offset),
+            None => Derivation::Time(Ok(self.load_time + offset)),
+        }
+    }
+
+    /// How the time of a message is derived, without resolving the message it depends on.
+    fn derivation(&self, i: usize) -> Derivation {
+        match &self.messages[i].timestamp {
+            Timestamp::Absolute(t) => Derivation::Time(Ok(*t)),
+            Timestamp::Relative(offset) => self.base(i, *offset),
+            Timestamp::Anchored { anchor, offset } => match self.anchors.get(anchor) {
+                Some(&j) => Derivation::From(j, *offset),
+                None => Derivation::Time(Err(anyhow!("Unknown anchor \"{}\"", anchor))),
+            },
+            Timestamp::Countdown(offset) => Derivation::Time(match self.deadline {
+                Some(deadline) => Ok(deadline - *offset),
+                None => Err(anyhow!("No deadline is set")),
+            }),
+            Timestamp::AfterReload(offset) => Derivation::Time(Ok(self.load_time + *offset)),
+            Timestamp::Every { .. }
+            | Timestamp::Recurring { .. }
+            | Timestamp::Daily(_)
+            | Timestamp::OnConnect => self.base(i, Duration::zero()),
+        }
+    }
+
+    /// Resolves the time of a message, for repeating messages this is the time they are anchored
+    /// at.
+    /// The messages it depends on are followed iteratively rather than recursively, as a script
+    /// can chain any number of messages together.
+    fn resolve(&mut self, i: usize) -> Result<DateTime<FixedOffset>> {
+        // Messages that are waiting for the time of the next message in the chain, along with the
+        // offset from it
+        let mut chain = Vec::new();
+        let mut current = i;
+
+        let mut result = loop {
+            match self.state[current] {
+                Resolution::Resolved(t) => break Ok(t),
+                Resolution::InProgress => break Err(anyhow!("Cyclic anchor reference")),
+                Resolution::Failed => {
+                    break Err(anyhow!("Depends on a message that could not be scheduled"))
+                }
+                Resolution::Pending => {}
+            }
+
+            match self.derivation(current) {
+                Derivation::Time(result) => {
+                    self.state[current] = Resolution::from(&result);
+                    break result;
+                }
+                Derivation::From(j, offset) => {
+                    self.state[current] = Resolution::InProgress;
+                    chain.push((current, offset));
+                    current = j;
+                }
+            }
+        };
+
+        while let Some((j, offset)) = chain.pop() {
+            result = result.map(|t| t + offset);
+            self.state[j] = Resolution::from(&result);
+        }
+
+        result
+    }
+}
+
+/// Replaces each "${VAR}" with the value of the environment variable VAR.
+/// Variables that are not set are an error if strict, otherwise they are replaced with an empty
+/// string.
+fn interpolate_env(s: &str, strict: bool) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        result.push_str(&rest[..start]);
+
+        let name = &rest[start + 2..end];
+        match std::env::var(name) {
+            Ok(value) => result.push_str(&value),
+            Err(e) if strict => {
+                return Err(anyhow!("Environment variable \"{}\": {}", name, e));
+            }
+            Err(e) => {
+                log::warn!(
+                    "Environment variable \"{}\": {}, substituting an empty string",
+                    name,
+                    e
+                );
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Substitutes environment variables in the topic and message fields of a record.
+fn interpolate_env_record(record: &StringRecord, strict: bool) -> Result<StringRecord> {
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match i {
+            1 | 2 => interpolate_env(field, strict),
+            _ => Ok(field.to_string()),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(StringRecord::from)
+}
+
+/// Parses a timestamp using the first of the given strptime style formats that matches.
+/// Formats without a timezone offset are interpreted in the given timezone.
+fn parse_with_formats<S: AsRef<str>>(
+    s: &str,
+    formats: &[S],
+    zone: Zone,
+) -> Option<DateTime<FixedOffset>> {
+    formats.iter().find_map(|format| {
+        DateTime::parse_from_str(s, format.as_ref())
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(s, format.as_ref())
+                    .ok()
+                    .and_then(|t| zone.resolve_naive(t).ok())
+            })
+    })
+}
+
+/// Describes where in a script file a record is, for error messages.
+fn location(source: &Path, line: Option<u64>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", source.display(), line),
+        None => source.display().to_string(),
+    }
+}
+
+/// Checks that a topic does not contain the null character, which MQTT forbids in topic names, or
+/// other control characters, which are most likely a mistake.
+fn validate_topic(topic: &str) -> Result<()> {
+    match topic.chars().find(|c| c.is_control()) {
+        Some(c) => Err(anyhow!(
+            "Topic {:?} contains the control character {:?}",
+            topic,
+            c
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Parses a single record of a script file into a message, along with the name of its anchor if
+/// it has one.
+fn parse_record(
+    options: &ScriptOptions,
+    mut record: StringRecord,
+) -> Result<(Option<String>, Message)> {
+    let anchor = take_anchor(&mut record);
+
+    // Timestamps in custom formats, and those without an offset, are converted to RFC3339 in the
+    // configured timezone, which the timestamp deserializer accepts
+    if let Some(t) = record.get(0) {
+        let t = match parse_with_formats(t, &options.timestamp_formats, options.timezone) {
+            Some(t) => Some(t),
+            None => match Timestamp::parse(t, options.timezone)? {
+                Timestamp::Absolute(t) => Some(t),
+                _ => None,
+            },
+        };
+        if let Some(t) = t {
+            record = std::iter::once(t.to_rfc3339().as_str())
+                .chain(record.iter().skip(1))
+                .collect();
+        }
+    }
+
+    // Rows with only a timestamp and a message are sent to the default topic
+    if record.len() == 2 {
+        let topic = options
+            .default_topic
+            .as_deref()
+            .ok_or_else(|| anyhow!("Row has no topic and no default topic is set"))?;
+        record = [&record[0], topic, &record[1]].into_iter().collect();
+    }
+
+    let record = interpolate_env_record(&record, options.strict_env)?;
+    let mut message = record.deserialize::<Message>(None)?;
+    validate_topic(&message.topic)?;
+    if let Some(path) = message
+        .message
+        .0
+        .strip_prefix(FILE_PAYLOAD_PREFIX.as_bytes())
+    {
+        message.payload_file = Some(PathBuf::from(String::from_utf8_lossy(path).into_owned()));
+        message.message = Payload::default();
+    }
+    Ok((anchor, message))
+}
+
+/// Removes whitespace around fields, outside of quotes, so that a quote following the whitespace
+/// after a delimiter still starts a quoted field.
+/// Line breaks are kept, so that the line numbers of records do not change.
+fn strip_field_whitespace(input: &[u8], delimiter: u8, quote: Option<u8>) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut whitespace = Vec::new();
+    let mut field_start = true;
+    let mut in_quotes = false;
+
+    let mut bytes = input.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        if in_quotes {
+            output.push(b);
+            if Some(b) == quote {
+                // A doubled quote is an escaped quote, rather than the end of the quoted field
+                if bytes.peek().copied() == quote {
+                    output.push(bytes.next().unwrap());
+                } else {
+                    in_quotes = false;
+                }
+            }
+        } else if b == b' ' || b == b'\t' {
+            whitespace.push(b);
+        } else if b == delimiter || b == b'\n' || b == b'\r' {
+            whitespace.clear();
+            output.push(b);
+            field_start = true;
+        } else {
+            if !field_start {
+                output.append(&mut whitespace);
+            }
+            whitespace.clear();
+            in_quotes = field_start && Some(b) == quote;
+            field_start = false;
+            output.push(b);
+        }
+    }
+
+    output
+}
+
+/// Reads the records of a script file, errors include the location of the record they relate to.
+fn read_records<R: Read>(
+    options: &ScriptOptions,
+    source: &Path,
+    mut reader: R,
+) -> Vec<Result<(Option<String>, Message)>> {
+    let mut input = Vec::new();
+    if let Err(e) = reader.read_to_end(&mut input) {
+        return vec![Err(anyhow!("{}: {}", source.display(), e))];
+    }
+    let input = strip_field_whitespace(&input, options.delimiter, options.quote);
+
+    ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(if options.trim_quoted {
+            Trim::All
+        } else {
+            Trim::None
+        })
+        .delimiter(options.delimiter)
+        .comment(Some(COMMENT))
+        .quoting(options.quote.is_some())
+        .quote(options.quote.unwrap_or(b'"'))
+        .from_reader(input.as_slice())
+        .into_records()
+        .map(|record| {
+            let line = match &record {
+                Ok(record) => record.position(),
+                Err(e) => e.position(),
+            }
+            .map(|p| p.line());
+
+            record
+                .map_err(anyhow::Error::from)
+                .and_then(|record| parse_record(options, record))
+                .map_err(|e| anyhow!("{}: {}", location(source, line), e))
+        })
+        .collect()
+}
+
+/// Prefix of lines in script files that include the messages of another script file.
+const INCLUDE_PREFIX: &str = "include:";
+
+/// Removes the include directives ("include:[path]" lines) from the contents of a script file,
+/// returning the contents without them and the included paths, in order.
+/// Directives are replaced with blank lines, so that the line numbers of records do not change.
+fn take_includes(contents: &str) -> (String, Vec<PathBuf>) {
+    let mut includes = Vec::new();
+
+    let contents = contents
+        .split('\n')
+        .map(|line| match line.trim().strip_prefix(INCLUDE_PREFIX) {
+            Some(path) => {
+                includes.push(PathBuf::from(path.trim()));
+                ""
+            }
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (contents, includes)
+}
+
+/// Loads the messages of a script file, along with the number of records that could not be parsed
+/// or scheduled.
+fn load_messages<R: Read>(
+    options: &ScriptOptions,
+    source: &Path,
+    load_time: DateTime<FixedOffset>,
+    reader: R,
+) -> (Vec<Message>, usize) {
+    let mut messages = Vec::new();
+    let mut errors = 0;
+    let mut anchors = HashMap::new();
+
+    for record in read_records(options, source, reader) {
+        match record {
+            Ok((anchor, m)) => {
+                if let Some(anchor) = anchor {
+                    match anchors.entry(anchor) {
+                        Entry::Occupied(e) => {
+                            warnings::warn(format!(
+                                "Anchor \"{}\" is defined more than once",
+                                e.key()
+                            ));
+                        }
+                        Entry::Vacant(e) => {
+                            e.insert(messages.len());
+                        }
+                    }
+                }
+                messages.push(m);
+            }
+            Err(e) => {
+                warnings::warn(format!("Failed to parse message script: {}", e));
+                errors += 1;
+            }
+        }
+    }
+
+    let mut resolver = Resolver::new(
+        &messages,
+        anchors,
+        load_time,
+        options.deadline,
+        options.relative_mode,
+    );
+    let resolved: Vec<_> = (0..messages.len()).map(|i| resolver.resolve(i)).collect();
+
+    let messages = messages
+        .into_iter()
+        .zip(resolved)
+        .filter_map(|(mut m, t)| {
+            let t = match t {
+                Ok(t) => t,
+                Err(e) => {
+                    warnings::warn(format!("Failed to schedule message {}: {}", m, e));
+                    errors += 1;
+                    return None;
+                }
+            };
+
+            m.timestamp = match m.timestamp {
+                Timestamp::Every { interval, count } => Timestamp::Recurring {
+                    next: t + interval,
+                    interval,
+                    remaining: count,
+                },
+                Timestamp::Daily(offset) => Timestamp::Daily(offset),
+                Timestamp::OnConnect => Timestamp::OnConnect,
+                // Scheduled from the time of each reload instead
+                Timestamp::AfterReload(offset) => Timestamp::AfterReload(offset),
+                _ => Timestamp::Absolute(t),
+            };
+
+            Some(m)
+        })
+        .flat_map(|m| fan_out(m, options.topic_separator))
+        .collect();
+
+    (messages, errors)
+}
+
+/// Expands a message with several topics, separated by the separator, into one message per topic.
+fn fan_out(m: Message, separator: char) -> Vec<Message> {
+    if !m.topic.contains(separator) {
+        return vec![m];
+    }
+
+    m.topic
+        .split(separator)
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .map(|topic| Message {
+            topic: topic.to_string(),
+            ..m.clone()
+        })
+        .collect()
+}
+
+fn load_state(path: &Path) -> Option<DateTime<FixedOffset>> {
+    let state = match fs::read_to_string(path) {
+        Ok(state) => state,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // The state file is created by the first poll
+            log::info!(
+                "State file {:?} does not exist yet, starting from now",
+                path
+            );
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Failed to read state file {:?}: {}", path, e);
+            return None;
+        }
+    };
+
+    match DateTime::parse_from_rfc3339(state.trim()) {
+        Ok(t) => {
+            log::info!("Restored last poll time {} from {:?}", t, path);
+            Some(t)
+        }
+        Err(e) => {
+            log::warn!("Failed to parse state file {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Patterns matching script files, either plain (".txt") or gzip compressed (".txt.gz").
+pub const DEFAULT_SCRIPT_GLOBS: &[&str] = &["**/*.txt", "**/*.txt.gz"];
+
+/// Patterns matching the names of temporary and backup files created by editors, which are never
+/// script files.
+pub const DEFAULT_IGNORE_PATTERNS: &[&str] = &["*.swp", "*.swo", "*.swx", "*~", "#*#", "*.tmp"];
+
+/// Options for matching script file glob patterns, shared by loading and watching so that both
+/// agree on which files are scripts (e.g. "a.TXT" matches "*.txt").
+const SCRIPT_GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Validates a glob pattern for script files, relative to the script directory.
+pub fn parse_script_glob(s: &str) -> Result<String> {
+    Pattern::new(s)?;
+    Ok(s.to_string())
+}
+
+/// Checks if a path should be ignored, because a file or directory on it starts with "." or the
+/// file name matches any of the ignore patterns.
+fn is_ignored(path: &Path, ignore: &[String]) -> bool {
+    let hidden = path
+        .iter()
+        .any(|i| i.to_str().is_some_and(|i| i.starts_with('.')));
+
+    let file_name = path.file_name().unwrap_or_default();
+    hidden
+        || ignore.iter().any(|g| match Pattern::new(g) {
+            Ok(pattern) => pattern.matches(&file_name.to_string_lossy()),
+            Err(_) => false,
+        })
+}
+
+/// Checks if a path in the script directory matches any of the script file glob patterns, and is
+/// not ignored.
+pub fn is_script_file(dir: &Path, globs: &[String], ignore: &[String], path: &Path) -> bool {
+    let relative_path = match path.strip_prefix(dir) {
+        Ok(relative_path) => relative_path,
+        Err(_) => return false,
+    };
+
+    if is_ignored(relative_path, ignore) {
+        return false;
+    }
+
+    globs.iter().any(|g| match Pattern::new(g) {
+        Ok(pattern) => pattern.matches_path_with(relative_path, SCRIPT_GLOB_OPTIONS),
+        Err(_) => false,
+    })
+}
+
+/// Finds the script files in a directory matching any of the glob patterns, recursively.
+/// Files and directories starting with "." and files matching any of the ignore patterns are
+/// ignored.
+pub fn find_script_files(dir: &Path, globs: &[String], ignore: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = BTreeSet::new();
+
+    for g in globs {
+        let pattern = format!("{}/{}", dir.display(), g);
+        for path in glob_with(&pattern, SCRIPT_GLOB_OPTIONS)? {
+            match path {
+                Ok(path) => {
+                    if is_ignored(&path, ignore) {
+                        log::debug!("Discarding path: {:?}", path);
+                    } else {
+                        paths.insert(path);
+                    }
+                }
+                Err(e) => log::debug!("Failed to read path: {}", e),
+            }
+        }
+    }
+
+    Ok(paths.into_iter().collect())
+}
+
+/// Drops the paths beyond the maximum number of files, if there is one, returning a warning if
+/// any were dropped.
+fn limit_files(paths: &mut Vec<PathBuf>, max_files: Option<usize>) -> Option<String> {
+    let max_files = max_files?;
+    if paths.len() <= max_files {
+        return None;
+    }
+
+    let found = paths.len();
+    paths.truncate(max_files);
+    Some(format!(
+        "Found {} script files, only loading the first {}",
+        found, max_files
+    ))
+}
+
+fn is_compressed(path: &Path) -> bool {
+    matches!(path.extension(), Some(ext) if ext.eq_ignore_ascii_case("gz"))
+}
+
+/// Placeholder in topics that is replaced with the path of the script file.
+const PATH_PLACEHOLDER: &str = "{{path}}";
+
+/// Start of a placeholder in topics that is replaced with a field of the (JSON) payload, of the
+/// form "{{json:field}}".
+const JSON_PLACEHOLDER_START: &str = "{{json:";
+
+const PLACEHOLDER_END: &str = "}}";
+
+/// Prefix of topics that start with a topic alias.
+const ALIAS_PREFIX: char = '@';
+
+/// Parses a topic alias given in the form "name=prefix".
+pub fn parse_topic_alias(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, prefix)) if !name.trim().is_empty() && !name.contains('/') => {
+            Ok((name.trim().to_string(), prefix.trim().to_string()))
+        }
+        _ => Err(anyhow!(
+            "Invalid topic alias \"{}\", expected the form \"name=prefix\"",
+            s
+        )),
+    }
+}
+
+/// Expands a topic of the form "@alias/rest" to the prefix the alias maps to, followed by the
+/// rest of the topic.
+/// Topics with an unknown alias are left as they are.
+fn expand_topic_alias(topic: &str, aliases: &HashMap<String, String>) -> String {
+    let aliased = match topic.strip_prefix(ALIAS_PREFIX) {
+        Some(aliased) => aliased,
+        None => return topic.to_string(),
+    };
+
+    let (name, rest) = match aliased.split_once('/') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (aliased, None),
+    };
+
+    match (aliases.get(name), rest) {
+        (Some(prefix), Some(rest)) => format!("{}/{}", prefix, rest),
+        (Some(prefix), None) => prefix.clone(),
+        (None, _) => {
+            warnings::warn(format!(
+                "Unknown topic alias \"{}\" in topic \"{}\"",
+                name, topic
+            ));
+            topic.to_string()
+        }
+    }
+}
+
+/// Replaces "{{json:field}}" placeholders in a topic with the value of that field of the
+/// payload, nested fields and array elements are separated by "." (e.g. "{{json:device.id}}").
+/// Placeholders are left as they are if the payload is not JSON or the field is not a string,
+/// number or boolean.
+fn expand_json_fields(topic: &str, payload: &Payload) -> String {
+    if !topic.contains(JSON_PLACEHOLDER_START) {
+        return topic.to_string();
+    }
+
+    let json = match serde_json::from_slice::<serde_json::Value>(&payload.0) {
+        Ok(json) => json,
+        Err(e) => {
+            warnings::warn(format!(
+                "Payload for topic \"{}\" is not valid JSON: {}",
+                topic, e
+            ));
+            return topic.to_string();
+        }
+    };
+
+    let mut expanded = String::new();
+    let mut rest = topic;
+    while let Some(start) = rest.find(JSON_PLACEHOLDER_START) {
+        expanded.push_str(&rest[..start]);
+        let field_start = start + JSON_PLACEHOLDER_START.len();
+        let end = match rest[field_start..].find(PLACEHOLDER_END) {
+            Some(end) => field_start + end,
+            None => break,
+        };
+        let placeholder = &rest[start..end + PLACEHOLDER_END.len()];
+        let field = &rest[field_start..end];
+
+        let pointer = format!("/{}", field.replace('.', "/"));
+        match json.pointer(&pointer) {
+            Some(serde_json::Value::String(value)) => expanded.push_str(value),
+            Some(value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => {
+                expanded.push_str(&value.to_string())
+            }
+            _ => {
+                warnings::warn(format!(
+                    "Payload for topic \"{}\" has no string, number or boolean field \"{}\"",
+                    topic, field
+                ));
+                expanded.push_str(placeholder);
+            }
+        }
+        rest = &rest[end + PLACEHOLDER_END.len()..];
+    }
+    expanded.push_str(rest);
+
+    expanded
+}
+
+pub const DEFAULT_TOPIC_SEPARATOR: char = ',';
+
+pub const DEFAULT_PATH_TEMPLATE: &str = "{dir}/{name}";
+
+/// Builds the topic segments for a script file, given its path relative to the script directory.
+/// In the template "{dir}" is replaced with the directory of the file and "{name}" with the file
+/// name, without extension.
+fn path_topic(template: &str, relative_path: &Path) -> String {
+    let dir = relative_path
+        .parent()
+        .map(|p| {
+            p.iter()
+                .map(|c| c.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .unwrap_or_default();
+
+    let name = relative_path
+        .file_stem()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+
+    template
+        .replace("{dir}", &dir)
+        .replace("{name}", &name)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Messages loaded from a single script file, along with the file metadata at the time it was
+/// loaded, used to detect whether the file has changed since.
+#[derive(Clone, Debug)]
+struct SourceFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// Hash of the contents of a fetched script, which has no metadata
+    digest: Option<u64>,
+    messages: Vec<Message>,
+}
+
+impl SourceFile {
+    fn is_unchanged(&self, metadata: &fs::Metadata) -> bool {
+        match (self.modified, metadata.modified()) {
+            (Some(modified), Ok(current)) => modified == current && self.len == metadata.len(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScriptOptions {
+    /// Delimiter between the columns of script files
+    pub delimiter: u8,
+    /// File used to persist the last poll time
+    pub state_file: Option<PathBuf>,
+    /// Log a warning for each loaded message that is in the past
+    pub warn_past: bool,
+    /// Template for the value substituted for "{{path}}" in topics
+    pub path_template: String,
+    /// Refuse to load scripts that contain colliding messages
+    pub strict: bool,
+    /// Topic of messages given without one, i.e. rows with only a timestamp and a message
+    pub default_topic: Option<String>,
+    /// Fail to create the script if it contains no messages or any errors when it is first loaded
+    pub strict_start: bool,
+    /// Topic prefixes that topics starting with "@[alias]" expand to
+    pub topic_aliases: HashMap<String, String>,
+    /// Skip messages that reference environment variables that are not set, instead of
+    /// substituting an empty string
+    pub strict_env: bool,
+    /// Separator between several topics of a message that is sent to each of them
+    pub topic_separator: char,
+    /// Character that quotes fields, e.g. so that they can contain the delimiter, or None to
+    /// disable quoting
+    pub quote: Option<u8>,
+    /// Also remove leading and trailing whitespace inside quoted fields
+    pub trim_quoted: bool,
+    /// Additional strptime style formats for absolute timestamps, tried in order before the
+    /// built in formats
+    pub timestamp_formats: Vec<String>,
+    /// Glob patterns matching script files, relative to the script directory
+    pub script_globs: Vec<String>,
+    /// Glob patterns matching the names of files that are never script files
+    pub ignore_patterns: Vec<String>,
+    /// Time since a file was last modified before it is loaded, so that files that are still
+    /// being written are not loaded
+    pub settle_time: std::time::Duration,
+    /// Period after which the script is replayed, with relative timestamps anchored to the start
+    /// of each period
+    pub loop_period: Option<LoopPeriod>,
+    /// Time that countdown timestamps ("deadline-[offset]") are relative to
+    pub deadline: Option<DateTime<FixedOffset>>,
+    /// What relative timestamps are offset from
+    pub relative_mode: RelativeMode,
+    /// Timezone of timestamps without an offset, daily entries and looping scripts
+    pub timezone: Zone,
+    /// Log every loaded message at info level, rather than only at debug level
+    pub log_loaded_messages: bool,
+    /// Maximum number of script files to load, in order of path
+    pub max_files: Option<usize>,
+    /// Fetches scripts that are loaded from a URL
+    pub fetcher: Fetcher,
+}
+
+impl Default for ScriptOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b'|',
+            state_file: None,
+            warn_past: false,
+            path_template: DEFAULT_PATH_TEMPLATE.to_string(),
+            strict: false,
+            strict_start: false,
+            default_topic: None,
+            topic_aliases: HashMap::new(),
+            strict_env: false,
+            topic_separator: DEFAULT_TOPIC_SEPARATOR,
+            quote: Some(b'"'),
+            trim_quoted: false,
+            timestamp_formats: Vec::new(),
+            script_globs: DEFAULT_SCRIPT_GLOBS.iter().map(|g| g.to_string()).collect(),
+            ignore_patterns: DEFAULT_IGNORE_PATTERNS
+                .iter()
+                .map(|g| g.to_string())
+                .collect(),
+            settle_time: std::time::Duration::ZERO,
+            loop_period: None,
+            deadline: None,
+            relative_mode: RelativeMode::default(),
+            timezone: Zone::default(),
+            log_loaded_messages: false,
+            max_files: None,
+            fetcher: Fetcher::default(),
+        }
+    }
+}
+
+/// What relative timestamps in a script file are offset from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum RelativeMode {
+    /// The previous message in the file, or the time the file was loaded for the first message
+    #[default]
+    Cumulative,
+    /// The time the file was loaded, for every message
+    Independent,
+}
+
+/// Period after which a looping script is replayed.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LoopPeriod {
+    /// Replay the script every day, starting at midnight
+    Daily,
+}
+
+impl LoopPeriod {
+    /// Start of the period that contains the given time.
+    fn start(&self, t: DateTime<FixedOffset>, zone: Zone) -> DateTime<FixedOffset> {
+        match self {
+            LoopPeriod::Daily => zone.start_of_day(zone.date(t)).unwrap_or(t),
+        }
+    }
+
+    /// Start of the period following the one that starts at the given time.
+    fn next(&self, start: DateTime<FixedOffset>, zone: Zone) -> DateTime<FixedOffset> {
+        match self {
+            LoopPeriod::Daily => zone
+                .date(start)
+                .succ_opt()
+                .and_then(|day| zone.start_of_day(day))
+                .unwrap_or(start + Duration::days(1)),
+        }
+    }
+}
+
+/// Overview of the messages that are still scheduled to be sent.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    /// Number of messages still due to be sent, repeating messages count once
+    pub count: usize,
+    /// Time the first message is next due
+    pub earliest: Option<DateTime<FixedOffset>>,
+    /// Time the last message is next due
+    pub latest: Option<DateTime<FixedOffset>>,
+}
+
+/// Source of the current time, which can be replaced so that tests can control time.
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> DateTime<FixedOffset> + Send + Sync>);
+
+impl Clock {
+    pub fn new(f: impl Fn() -> DateTime<FixedOffset> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    fn now(&self) -> DateTime<FixedOffset> {
+        (self.0)()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(now)
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// Time to wait for a script to be fetched from a URL.
+const FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Returns the URL of a script that is fetched over HTTP(S) rather than loaded from a directory.
+pub fn script_url(source: &Path) -> Option<&str> {
+    source
+        .to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+fn fetch_url(url: &str) -> Result<String> {
+    Ok(ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()?
+        .into_string()?)
+}
+
+/// Function fetching the body of a URL.
+type FetchFn = dyn Fn(&str) -> Result<String> + Send + Sync;
+
+/// Fetches the body of a URL, which can be replaced so that tests do not need an HTTP server.
+#[derive(Clone)]
+pub struct Fetcher(Arc<FetchFn>);
+
+impl Fetcher {
+    pub fn new(f: impl Fn(&str) -> Result<String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    fn fetch(&self, url: &str) -> Result<String> {
+        (self.0)(url)
+    }
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self::new(fetch_url)
+    }
+}
+
+impl fmt::Debug for Fetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Fetcher")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Script {
+    source_dir: PathBuf,
+    options: ScriptOptions,
+    clock: Clock,
+
+    files: BTreeMap<PathBuf, SourceFile>,
+
+    last_poll_time: DateTime<FixedOffset>,
+
+    /// Start of the current period of a looping script
+    loop_start: Option<DateTime<FixedOffset>>,
+
+    /// Whether files were skipped by the last reload because they were still being written
+    unsettled: bool,
+
+    /// Number of files and records that could not be loaded by the last reload
+    errors: usize,
+
+    /// Topics and times of one-off messages that were cancelled, which are not sent even if the
+    /// file they are in is loaded again
+    cancelled: HashSet<(String, DateTime<FixedOffset>)>,
+
+    /// Time the script was last (re)loaded, which reload messages are scheduled from
+    reloaded_at: DateTime<FixedOffset>,
+}
+
+/// Outcome of cancelling a scheduled message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cancellation {
+    /// The given number of messages were cancelled
+    Cancelled(usize),
+    /// The time has already been polled, so any messages scheduled then were already sent
+    AlreadySent,
+    /// There is no one-off message to the topic scheduled at the time
+    NotFound,
+}
+
+impl Script {
+    pub fn new(dir: &Path, options: ScriptOptions) -> Result<Self> {
+        Self::with_clock(dir, options, Clock::default())
+    }
+
+    pub fn with_clock(dir: &Path, options: ScriptOptions, clock: Clock) -> Result<Self> {
+        let last_poll_time = options
+            .state_file
+            .as_deref()
+            .and_then(load_state)
+            .unwrap_or_else(|| clock.now());
+
+        let loop_start = options
+            .loop_period
+            .map(|period| period.start(last_poll_time, options.timezone));
+
+        let mut s = Script {
+            source_dir: dir.to_path_buf(),
+            options,
+            clock,
+            files: BTreeMap::new(),
+            last_poll_time,
+            loop_start,
+            unsettled: false,
+            errors: 0,
+            cancelled: HashSet::new(),
+            reloaded_at: last_poll_time,
+        };
+
+        if let Err(e) = s.reload() {
+            if s.options.strict || s.options.strict_start {
+                return Err(e);
+            }
+            log::error!("Initial script loading failed: {}", e);
+        }
+
+        if s.options.strict_start {
+            if s.errors > 0 {
+                return Err(anyhow!(
+                    "Script contains {} files or messages that could not be loaded",
+                    s.errors
+                ));
+            }
+            if s.is_empty() {
+                return Err(anyhow!("Script in {:?} contains no messages", dir));
+            }
+        }
+
+        Ok(s)
+    }
+
+    pub fn last_poll_time(&self) -> DateTime<FixedOffset> {
+        self.last_poll_time
+    }
+
+    fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.files.values().flat_map(|f| f.messages.iter())
+    }
+
+    /// Reloads script files that have been added, changed or removed since the last reload.
+    ///
+    /// The last poll time is preserved, so each poll window (last poll, now] is only ever
+    /// covered once regardless of reloads:
+    ///  - messages in a reloaded file scheduled after the last poll are sent by the next poll,
+    ///    including those scheduled between the last poll and the reload
+    ///  - messages scheduled at or before the last poll are never sent, even if they were not in
+    ///    the script when that poll happened
+    ///  - messages of unchanged files keep their schedule, but relative and repeating messages of
+    ///    a file that is parsed again are rescheduled from the time it is reloaded
+    ///
+    /// A script fetched from a URL is fetched again on every reload, and only parsed again if it
+    /// has changed. If it cannot be fetched the previously loaded script is kept.
+    ///
+    /// Reload messages of all files, changed or not, are scheduled again from the time of a
+    /// successful reload.
+    pub fn reload(&mut self) -> Result<()> {
+        log::debug!("Building script from \"{}\"", &self.source_dir.display());
+
+        // Kept so that a script with collisions can be rejected in strict mode
+        let previous_files = self.options.strict.then(|| self.files.clone());
+
+        let source_dir = self.source_dir.clone();
+        match script_url(&source_dir) {
+            Some(url) => self.reload_url(url)?,
+            None => self.reload_files()?,
+        }
+
+        let collisions = self.collisions();
+        for (a, b) in &collisions {
+            log::warn!(
+                "Messages collide, delivery order is undefined: {} and {}",
+                a,
+                b
+            );
+        }
+        if let Some(previous_files) = previous_files {
+            if !collisions.is_empty() {
+                let count = collisions.len();
+                self.files = previous_files;
+                return Err(anyhow!(
+                    "Script contains {} colliding messages, keeping the previous script",
+                    count
+                ));
+            }
+        }
+
+        let loaded = self.log_loaded_messages();
+
+        let counts = self.file_message_counts();
+        log::info!("Loaded {} messages from {} files:", loaded, counts.len());
+        for (path, count) in counts {
+            // A fetched script is its own source, so it is logged with its URL
+            let path = match path.strip_prefix(&self.source_dir) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => path,
+            };
+            log::info!("  {}: {} messages", path.display(), count);
+        }
+
+        let past = self.past_messages();
+        if self.options.warn_past {
+            for m in &past {
+                log::warn!("Message is in the past and will not be sent: {}", m);
+            }
+        }
+        if !past.is_empty() {
+            log::info!(
+                "{} loaded messages are in the past and will not be sent",
+                past.len()
+            );
+        }
+
+        self.reloaded_at = self.clock.now();
+
+        Ok(())
+    }
+
+    /// Reloads the script files in the script directory that have been added, changed or removed.
+    fn reload_files(&mut self) -> Result<()> {
+        let mut paths = find_script_files(
+            &self.source_dir,
+            &self.options.script_globs,
+            &self.options.ignore_patterns,
+        )?;
+        if let Some(warning) = limit_files(&mut paths, self.options.max_files) {
+            warnings::warn(warning);
+        }
+
+        self.unsettled = false;
+        self.errors = 0;
+
+        self.files.retain(|path, _| {
+            let exists = paths.contains(path);
+            if !exists {
+                log::info!("Dropping removed file {:?}", path);
+            }
+            exists
+        });
+
+        for path in paths {
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    log::warn!("Failed to read metadata of {:?}: {}", path, e);
+                    self.files.remove(&path);
+                    continue;
+                }
+            };
+
+            if let Some(file) = self.files.get(&path) {
+                if file.is_unchanged(&metadata) {
+                    log::debug!("File {:?} is unchanged", path);
+                    continue;
+                }
+            }
+
+            if self.is_being_written(&metadata) {
+                log::debug!("File {:?} was modified too recently to load", path);
+                self.unsettled = true;
+                continue;
+            }
+
+            log::info!("Loading file {:?}", path);
+            match self.load_file(&path) {
+                Ok((messages, errors)) => {
+                    self.errors += errors;
+                    self.files.insert(
+                        path,
+                        SourceFile {
+                            modified: metadata.modified().ok(),
+                            len: metadata.len(),
+                            digest: None,
+                            messages,
+                        },
+                    );
+                }
+                Err(e) => {
+                    warnings::warn(format!("Failed to load {:?}: {}", path, e));
+                    self.errors += 1;
+                    self.files.remove(&path);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the script from a URL, replacing the previously loaded script if it has changed.
+    fn reload_url(&mut self, url: &str) -> Result<()> {
+        let contents = self
+            .options
+            .fetcher
+            .fetch(url)
+            .map_err(|e| anyhow!("Failed to fetch {}: {}", url, e))?;
+
+        self.unsettled = false;
+        self.errors = 0;
+
+        let path = PathBuf::from(url);
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let digest = Some(hasher.finish());
+
+        if self
+            .files
+            .get(&path)
+            .is_some_and(|file| file.digest == digest)
+        {
+            log::debug!("Script at {} is unchanged", url);
+            return Ok(());
+        }
+
+        log::info!("Loading script from {}", url);
+        let (contents, includes) = take_includes(&contents);
+        if !includes.is_empty() {
+            warnings::warn(format!(
+                "Includes are not supported in the script at {}",
+                url
+            ));
+            self.errors += includes.len();
+        }
+
+        let load_time = self.loop_start.unwrap_or_else(|| self.clock.now());
+        let (mut messages, errors) =
+            load_messages(&self.options, &path, load_time, contents.as_bytes());
+        self.errors += errors;
+
+        let name = Path::new(url.rsplit('/').next().unwrap_or_default());
+        self.expand_topics(
+            &mut messages,
+            &path_topic(&self.options.path_template, name),
+        );
+
+        self.files.clear();
+        self.files.insert(
+            path,
+            SourceFile {
+                modified: None,
+                len: contents.len() as u64,
+                digest,
+                messages,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Logs every loaded message, at info level if enabled in the options and at debug level
+    /// otherwise, returning the number of loaded messages.
+    fn log_loaded_messages(&self) -> usize {
+        let level = if self.options.log_loaded_messages {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+
+        let mut messages: Vec<&Message> = self.messages().collect();
+        if log::log_enabled!(level) {
+            // This sort is not strictly necessary, the core functionality will work mostly the
+            // same without it. It is just here to provide logical ordering for logging.
+            messages.sort_by(|a, b| a.schedule_cmp(b));
+
+            log::log!(level, "Loaded messages:");
+            for m in &messages {
+                log::log!(level, "{}", m);
+            }
+        }
+
+        messages.len()
+    }
+
+    /// Number of messages loaded from each script file, in order of path.
+    /// Files that are loaded but contain no valid messages are included with a count of zero.
+    pub fn file_message_counts(&self) -> Vec<(&Path, usize)> {
+        self.files
+            .iter()
+            .map(|(path, file)| (path.as_path(), file.messages.len()))
+            .collect()
+    }
+
+    /// Checks if a file was modified within the settle time, in which case it may still be being
+    /// written and only part of it would be loaded.
+    fn is_being_written(&self, metadata: &fs::Metadata) -> bool {
+        let settle_time = match Duration::from_std(self.options.settle_time) {
+            Ok(settle_time) if !settle_time.is_zero() => settle_time,
+            _ => return false,
+        };
+
+        match metadata.modified() {
+            Ok(modified) => {
+                let age = self
+                    .clock
+                    .now()
+                    .signed_duration_since(DateTime::<Local>::from(modified));
+                age >= Duration::zero() && age < settle_time
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Checks if the last reload skipped files that were still being written, which are loaded by
+    /// reloading again once they have not been modified for the settle time.
+    pub fn has_unsettled_files(&self) -> bool {
+        self.unsettled
+    }
+
+    /// Loads the messages of a script file and the files it includes, along with the number of
+    /// records and included files that could not be loaded.
+    fn load_file(&self, path: &Path) -> Result<(Vec<Message>, usize)> {
+        self.load_file_including(path, &mut Vec::new())
+    }
+
+    /// Loads a script file that is included by the files in `including` (canonical paths),
+    /// outermost first, which are not included again so that include cycles are broken.
+    fn load_file_including(
+        &self,
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<(Vec<Message>, usize)> {
+        let file = File::open(path)?;
+        let mut reader: Box<dyn Read> = if is_compressed(path) {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        // Checked up front, as otherwise every record of a file that is not text fails to parse
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        let contents = String::from_utf8(contents)
+            .map_err(|e| anyhow!("File is not valid UTF-8: {}", e.utf8_error()))?;
+        let (contents, includes) = take_includes(&contents);
+
+        // Relative timestamps of a looping script are anchored to the start of the current period
+        let load_time = self.loop_start.unwrap_or_else(|| self.clock.now());
+        let (mut messages, mut errors) =
+            load_messages(&self.options, path, load_time, contents.as_bytes());
+
+        let relative_path = path.strip_prefix(&self.source_dir).unwrap_or(path);
+        self.expand_topics(
+            &mut messages,
+            &path_topic(&self.options.path_template, relative_path),
+        );
+        for m in &mut messages {
+            // File payloads and conditions are relative to the directory of the script file
+            if let (Some(file), Some(dir)) = (&m.payload_file, path.parent()) {
+                m.payload_file = Some(dir.join(file));
+            }
+            if let (Some(file), Some(dir)) = (&m.condition, path.parent()) {
+                m.condition = Some(dir.join(file));
+            }
+        }
+
+        including.push(fs::canonicalize(path)?);
+        for include in includes {
+            // Included files are relative to the directory of the including file
+            let include = match path.parent() {
+                Some(dir) => dir.join(include),
+                None => include,
+            };
+
+            let result = fs::canonicalize(&include)
+                .map_err(anyhow::Error::from)
+                .and_then(|canonical| {
+                    if including.contains(&canonical) {
+                        Err(anyhow!("Include cycle"))
+                    } else {
+                        self.load_file_including(&include, including)
+                    }
+                });
+
+            match result {
+                Ok((included, included_errors)) => {
+                    log::debug!("Included {} messages from {:?}", included.len(), include);
+                    messages.extend(included);
+                    errors += included_errors;
+                }
+                Err(e) => {
+                    warnings::warn(format!(
+                        "Failed to include {:?} in {:?}: {}",
+                        include, path, e
+                    ));
+                    errors += 1;
+                }
+            }
+        }
+        including.pop();
+
+        Ok((messages, errors))
+    }
+
+    /// Expands the topic aliases, path placeholders and payload field placeholders in the topics of
+    /// messages loaded from a script file.
+    fn expand_topics(&self, messages: &mut [Message], path_topic: &str) {
+        for m in messages {
+            m.topic = expand_topic_alias(&m.topic, &self.options.topic_aliases)
+                .replace(PATH_PLACEHOLDER, path_topic);
+            m.topic = expand_json_fields(&m.topic, &m.message);
+            m.else_topic = m.else_topic.as_ref().map(|topic| {
+                expand_topic_alias(topic, &self.options.topic_aliases)
+                    .replace(PATH_PLACEHOLDER, path_topic)
+            });
+        }
+    }
+
+    /// Pairs of messages with the same topic scheduled in the same second but with different
+    /// payloads, these are most likely a mistake as the order they are sent in is undefined.
+    fn collisions(&self) -> Vec<(&Message, &Message)> {
+        let mut seen: HashMap<(&str, i64), &Message> = HashMap::new();
+        let mut collisions = Vec::new();
+
+        for m in self.messages() {
+            if let Timestamp::Absolute(t) = m.timestamp {
+                match seen.entry((m.topic.as_str(), t.timestamp())) {
+                    Entry::Occupied(e) => {
+                        if e.get().message != m.message {
+                            collisions.push((*e.get(), m));
+                        }
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(m);
+                    }
+                }
+            }
+        }
+
+        collisions
+    }
+
+    /// Messages that are scheduled before the last poll, and therefore will never be sent.
+    fn past_messages(&self) -> Vec<&Message> {
+        self.messages()
+            .filter(|m| match m.timestamp {
+                Timestamp::Absolute(t) => t <= self.last_poll_time,
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Checks if there are messages that repeat indefinitely, in which case the script is never
+    /// exhausted.
+    pub fn is_unbounded(&self) -> bool {
+        self.messages().any(|m| {
+            matches!(
+                m.timestamp,
+                Timestamp::Recurring {
+                    remaining: None,
+                    ..
+                } | Timestamp::Daily(_)
+            )
+        })
+    }
+
+    /// Cancels the one-off messages to a topic scheduled at a time, so that they are not sent.
+    /// Repeating messages can not be cancelled.
+    pub fn cancel(&mut self, topic: &str, time: DateTime<FixedOffset>) -> Cancellation {
+        if time <= self.last_poll_time {
+            return Cancellation::AlreadySent;
+        }
+
+        let count = self
+            .messages()
+            .filter(|m| m.topic == topic && m.timestamp == Timestamp::Absolute(time))
+            .count();
+        if count == 0 {
+            return Cancellation::NotFound;
+        }
+
+        self.cancelled.insert((topic.to_string(), time));
+        Cancellation::Cancelled(count)
+    }
+
+    fn is_cancelled(&self, topic: &str, time: DateTime<FixedOffset>) -> bool {
+        self.cancelled.contains(&(topic.to_string(), time))
+    }
+
+    /// Messages that are still due to be sent after the last poll, at least once.
+    fn scheduled_messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages().filter(|m| match m.timestamp {
+            Timestamp::Absolute(t) => t > self.last_poll_time && !self.is_cancelled(&m.topic, t),
+            Timestamp::AfterReload(offset) => self.reloaded_at + offset > self.last_poll_time,
+            Timestamp::Recurring { remaining, .. } => remaining != Some(0),
+            Timestamp::OnConnect => false,
+            _ => true,
+        })
+    }
+
+    /// Messages that are sent whenever a connection to a broker is established.
+    pub fn on_connect_messages(&self) -> Vec<Message> {
+        self.messages()
+            .filter(|m| m.timestamp == Timestamp::OnConnect)
+            .cloned()
+            .collect()
+    }
+
+    /// The number of messages that are still due to be sent, repeating messages count once.
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled_messages().count()
+    }
+
+    pub fn summary(&self) -> Summary {
+        let times: Vec<_> = self
+            .scheduled_messages()
+            .filter_map(|m| m.timestamp.scheduled_time())
+            .collect();
+
+        Summary {
+            count: self.scheduled_count(),
+            earliest: times.iter().min().copied(),
+            latest: times.iter().max().copied(),
+        }
+    }
+
+    /// Checks if the script contains no messages at all, e.g. because every script file was
+    /// removed.
+    pub fn is_empty(&self) -> bool {
+        self.messages().next().is_none()
+    }
+
+    /// Checks if all messages have been sent, i.e. there are no messages left that are due after
+    /// the last poll.
+    pub fn is_exhausted(&self) -> bool {
+        self.scheduled_messages().next().is_none()
+    }
+
+    /// Collects the messages that became due since the last poll, in the order they should be
+    /// sent.
+    ///
+    /// ```
+    /// use chrono::{DateTime, Duration};
+    /// use mqtt_actor::script::{Clock, Script, ScriptOptions};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let dir = tempfile::Builder::new().prefix("mqtt-actor").tempdir()?;
+    /// std::fs::write(dir.path().join("example.txt"), "5 | test_topic | hello")?;
+    ///
+    /// // A clock that is advanced manually, Script::new uses the system clock
+    /// let start = DateTime::parse_from_rfc3339("2022-03-28T10:00:00+00:00")?;
+    /// let time = Arc::new(Mutex::new(start));
+    /// let clock = {
+    ///     let time = time.clone();
+    ///     Clock::new(move || *time.lock().unwrap())
+    /// };
+    ///
+    /// let mut script = Script::with_clock(dir.path(), ScriptOptions::default(), clock)?;
+    /// assert!(script.poll().is_empty());
+    ///
+    /// *time.lock().unwrap() = start + Duration::seconds(5);
+    /// let messages = script.poll();
+    /// assert_eq!(messages.len(), 1);
+    /// assert_eq!(messages[0].topic, "test_topic");
+    /// assert_eq!(messages[0].message.to_string_lossy(), "hello");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn poll(&mut self) -> Vec<Message> {
+        let end = self.clock.now();
+        let msgs = self.take_due(end, None);
+
+        self.last_poll_time = end;
+
+        if let Err(e) = self.save_state() {
+            log::error!("Failed to save state: {}", e);
+        }
+
+        msgs
+    }
+
+    /// Messages that are due to be sent after the last poll, up to and including the given time,
+    /// without advancing the schedule.
+    /// At most `max_occurrences` occurrences of each repeating message are included.
+    pub fn upcoming(&self, end: DateTime<FixedOffset>, max_occurrences: usize) -> Vec<Message> {
+        self.clone().take_due(end, Some(max_occurrences))
+    }
+
+    /// Collects the messages that are due between the last poll and the given time, replaying a
+    /// looping script from the start of each period that begins in the window.
+    fn take_due(
+        &mut self,
+        end: DateTime<FixedOffset>,
+        max_occurrences: Option<usize>,
+    ) -> Vec<Message> {
+        let mut msgs = Vec::new();
+
+        while let (Some(period), Some(start)) = (self.options.loop_period, self.loop_start) {
+            let next = period.next(start, self.options.timezone);
+            if next > end {
+                break;
+            }
+
+            msgs.extend(self.take_due_in_window(next, max_occurrences));
+            self.last_poll_time = next;
+
+            log::info!("Replaying script from {}", next.to_rfc3339());
+            self.loop_start = Some(next);
+            self.files.clear();
+            if let Err(e) = self.reload() {
+                log::error!("Failed to reload script: {}", e);
+            }
+        }
+
+        msgs.extend(self.take_due_in_window(end, max_occurrences));
+        msgs
+    }
+
+    /// Collects the messages that are due between the last poll and the given time, advancing
+    /// repeating messages past the end of the window.
+    fn take_due_in_window(
+        &mut self,
+        end: DateTime<FixedOffset>,
+        max_occurrences: Option<usize>,
+    ) -> Vec<Message> {
+        let start = self.last_poll_time;
+        log::trace!("Polling window ({}, {}]", start, end);
+
+        let mut msgs = Vec::new();
+        let cancelled = &self.cancelled;
+        let reloaded_at = self.reloaded_at;
+
+        // The log macros only format their arguments if the level is enabled, so the per-message
+        // tracing below costs nothing by default
+        for m in self.files.values_mut().flat_map(|f| f.messages.iter_mut()) {
+            match m.timestamp {
+                Timestamp::Absolute(t)
+                    if t > start && t <= end && cancelled.contains(&(m.topic.clone(), t)) =>
+                {
+                    log::info!("Not sending cancelled message: {}", m);
+                }
+                Timestamp::Absolute(t) if t > start && t <= end => {
+                    log::trace!("Due in window: {}", m);
+                    msgs.push(m.clone());
+                }
+                Timestamp::Absolute(t) if t <= start => {
+                    log::trace!("Not due, before window: {}", m);
+                }
+                Timestamp::Absolute(_) => {
+                    log::trace!("Not due, after window: {}", m);
+                }
+                Timestamp::Recurring {
+                    mut next,
+                    interval,
+                    mut remaining,
+                } => {
+                    let mut occurrences = 0;
+                    while next <= end
+                        && remaining != Some(0)
+                        && max_occurrences.is_none_or(|max| occurrences < max)
+                    {
+                        if next > start {
+                            occurrences += 1;
+                            let mut occurrence = m.clone();
+                            occurrence.timestamp = Timestamp::Absolute(next);
+                            msgs.push(occurrence);
+                        }
+                        next += interval;
+                        remaining = remaining.map(|r| r - 1);
+                    }
+                    log::trace!(
+                        "{} occurrences due in window, next at {}: {}",
+                        occurrences,
+                        next,
+                        m
+                    );
+                    m.timestamp = Timestamp::Recurring {
+                        next,
+                        interval,
+                        remaining,
+                    };
+                }
+                Timestamp::Daily(offset) => {
+                    for t in self
+                        .options
+                        .timezone
+                        .daily_occurrences(offset, start, end)
+                        .into_iter()
+                        .take(max_occurrences.unwrap_or(usize::MAX))
+                    {
+                        log::trace!("Daily occurrence at {} due in window: {}", t, m);
+                        let mut occurrence = m.clone();
+                        occurrence.timestamp = Timestamp::Absolute(t);
+                        msgs.push(occurrence);
+                    }
+                }
+                Timestamp::OnConnect => {
+                    log::trace!("Not due, sent on connect: {}", m);
+                }
+                Timestamp::AfterReload(offset) => {
+                    let t = reloaded_at + offset;
+                    if t > start && t <= end {
+                        log::trace!("Due in window after reload at {}: {}", reloaded_at, m);
+                        let mut occurrence = m.clone();
+                        occurrence.timestamp = Timestamp::Absolute(t);
+                        msgs.push(occurrence);
+                    }
+                }
+                _ => {
+                    log::trace!("Not due, time is not resolved: {}", m);
+                }
+            }
+        }
+
+        // Ensures messages falling within the same poll() time window are delivered in timestamp
+        // order, regardless of which file they came from, and in the same order every time
+        msgs.sort_by(Message::schedule_cmp);
+
+        // Cancellations are only needed until the time they are for has passed
+        self.cancelled.retain(|(_, t)| *t > end);
+
+        msgs
+    }
+
+    /// Writes the last poll time to the state file.
+    /// It is written to a temporary file that then replaces the state file, so that the state file
+    /// is never left partially written (e.g. if the process is killed).
+    fn save_state(&self) -> Result<()> {
+        if let Some(path) = &self.options.state_file {
+            let mut temp = path.as_os_str().to_owned();
+            temp.push(".tmp");
+            fs::write(&temp, self.last_poll_time.to_rfc3339())?;
+            fs::rename(&temp, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone};
+    use std::{io::Cursor, sync::Mutex};
+    use tempfile::TempDir;
+
+    fn test_dir() -> TempDir {
+        // The default prefix starts with a ".", which would cause all files to be discarded
+        tempfile::Builder::new()
+            .prefix("mqtt-actor")
+            .tempdir()
+            .unwrap()
+    }
+
+    /// A time at the given UTC date and time, with a zero offset.
+    fn utc(
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        min: u32,
+        sec: u32,
+    ) -> DateTime<FixedOffset> {
+        Utc.with_ymd_and_hms(year, month, day, hour, min, sec)
+            .unwrap()
+            .fixed_offset()
+    }
+
+    fn script_with_messages(
+        messages: Vec<Message>,
+        last_poll_time: DateTime<FixedOffset>,
+    ) -> Script {
+        let mut files = BTreeMap::new();
+        files.insert(
+            PathBuf::from("test.txt"),
+            SourceFile {
+                modified: None,
+                len: 0,
+                digest: None,
+                messages,
+            },
+        );
+
+        Script {
+            source_dir: PathBuf::new(),
+            options: ScriptOptions::default(),
+            clock: Clock::default(),
+            files,
+            last_poll_time,
+            loop_start: None,
+            unsettled: false,
+            errors: 0,
+            cancelled: HashSet::new(),
+            reloaded_at: last_poll_time,
+        }
+    }
+
+    /// A clock that only moves when the returned time is changed.
+    fn manual_clock(t: DateTime<FixedOffset>) -> (Clock, Arc<Mutex<DateTime<FixedOffset>>>) {
+        let time = Arc::new(Mutex::new(t));
+        let clock = {
+            let time = time.clone();
+            Clock::new(move || *time.lock().unwrap())
+        };
+        (clock, time)
+    }
+
+    #[test]
+    fn timestamp_parse_absolute() {
+        assert_eq!(
+            Timestamp::from_str("2022-03-28T10:23:33+00:00").unwrap(),
+            Timestamp::Absolute(utc(2022, 3, 28, 10, 23, 33))
+        );
+
+        assert_eq!(
+            Timestamp::from_str("Mon, 28 Mar 2022 10:23:33 GMT").unwrap(),
+            Timestamp::Absolute(utc(2022, 3, 28, 10, 23, 33))
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_naive() {
+        let expected = DateTime::from(Local.with_ymd_and_hms(2022, 3, 28, 10, 23, 33).unwrap());
+
+        assert_eq!(
+            Timestamp::from_str("2022-03-28 10:23:33").unwrap(),
+            Timestamp::Absolute(expected)
+        );
+
+        assert_eq!(
+            Timestamp::from_str("2022-03-28T10:23:33").unwrap(),
+            Timestamp::Absolute(expected)
+        );
+
+        assert_eq!(
+            Timestamp::from_str("2022-03-28 10:23:33.500").unwrap(),
+            Timestamp::Absolute(expected + Duration::milliseconds(500))
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_naive_in_zone() {
+        let zone = Zone::Named(chrono_tz::Asia::Tokyo);
+        assert_eq!(
+            Timestamp::parse("2022-03-28 10:23:33", zone).unwrap(),
+            Timestamp::Absolute(utc(2022, 3, 28, 1, 23, 33))
+        );
+
+        // Skipped by the change to BST in London
+        let zone = Zone::Named(chrono_tz::Europe::London);
+        assert!(Timestamp::parse("2022-03-27 01:30:00", zone).is_err());
+    }
+
+    #[test]
+    fn resolve_naive_fixed_offset() {
+        let t = NaiveDateTime::parse_from_str("2022-03-28 10:23:33", "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(
+            resolve_naive(t, &FixedOffset::east_opt(3600).unwrap()).unwrap(),
+            utc(2022, 3, 28, 9, 23, 33)
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_relative() {
+        assert_eq!(
+            Timestamp::from_str("25").unwrap(),
+            Timestamp::Relative(Duration::seconds(25))
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_relative_units() {
+        assert_eq!(
+            Timestamp::from_str("90s").unwrap(),
+            Timestamp::Relative(Duration::seconds(90))
+        );
+        assert_eq!(
+            Timestamp::from_str("5m").unwrap(),
+            Timestamp::Relative(Duration::minutes(5))
+        );
+        assert_eq!(
+            Timestamp::from_str("2h").unwrap(),
+            Timestamp::Relative(Duration::hours(2))
+        );
+        assert_eq!(
+            Timestamp::from_str("1d").unwrap(),
+            Timestamp::Relative(Duration::days(1))
+        );
+        assert!(Timestamp::from_str("2x").is_err());
+        assert!(Timestamp::from_str("h").is_err());
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert_eq!(
+            parse_duration("106751991167d").unwrap(),
+            Duration::days(106_751_991_167)
+        );
+        assert!(parse_duration("106751991168d").is_err());
+        assert!(parse_duration(&format!("{}h", i64::MAX / 60)).is_err());
+        assert!(parse_duration(&format!("{}s", i64::MAX)).is_err());
+        assert!(Timestamp::from_str(&format!("{}d", i64::MAX)).is_err());
+    }
+
+    #[test]
+    fn timestamp_parse_every() {
+        assert_eq!(
+            Timestamp::from_str("every:30s").unwrap(),
+            Timestamp::Every {
+                interval: Duration::seconds(30),
+                count: None
+            }
+        );
+
+        assert_eq!(
+            Timestamp::from_str("every:5m:10").unwrap(),
+            Timestamp::Every {
+                interval: Duration::minutes(5),
+                count: Some(10)
+            }
+        );
+
+        assert_eq!(
+            Timestamp::from_str("every:90").unwrap(),
+            Timestamp::Every {
+                interval: Duration::seconds(90),
+                count: None
+            }
+        );
+
+        assert!(Timestamp::from_str("every:0s").is_err());
+        assert!(Timestamp::from_str("every:30s:x").is_err());
+        assert!(Timestamp::from_str("every:").is_err());
+    }
+
+    #[test]
+    fn messages_with_every() {
+        let data = r##"
+Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | msg 1
+every:10s:3                   | root/user-2 | msg 2
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(
+            msgs[1].timestamp,
+            Timestamp::Recurring {
+                next: utc(2022, 3, 28, 0, 0, 10),
+                interval: Duration::seconds(10),
+                remaining: Some(3),
+            }
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_midnight() {
+        assert_eq!(
+            Timestamp::from_str("midnight+28800").unwrap(),
+            Timestamp::Daily(Duration::hours(8))
+        );
+
+        assert_eq!(
+            Timestamp::from_str("midnight+90m").unwrap(),
+            Timestamp::Daily(Duration::minutes(90))
+        );
+
+        assert_eq!(
+            Timestamp::from_str("midnight").unwrap(),
+            Timestamp::Daily(Duration::zero())
+        );
+
+        assert!(Timestamp::from_str("midnight+86400").is_err());
+        assert!(Timestamp::from_str("midnight+-10").is_err());
+        assert!(Timestamp::from_str("midnight-10").is_err());
+    }
+
+    #[test]
+    fn timestamp_parse_epoch() {
+        let expected = utc(2022, 3, 28, 10, 23, 33);
+
+        assert_eq!(
+            Timestamp::from_str("epoch:1648463013").unwrap(),
+            Timestamp::Absolute(expected)
+        );
+        assert_eq!(
+            Timestamp::from_str("epoch:0").unwrap(),
+            Timestamp::Absolute(utc(1970, 1, 1, 0, 0, 0))
+        );
+        assert!(Timestamp::from_str("epoch:").is_err());
+        assert!(Timestamp::from_str("epoch:1.5").is_err());
+        assert!(Timestamp::from_str("epoch:99999999999999999").is_err());
+
+        assert_eq!(
+            Timestamp::from_str("epochms:1648463013250").unwrap(),
+            Timestamp::Absolute(expected + Duration::milliseconds(250))
+        );
+        assert_eq!(
+            Timestamp::from_str("epochms:-1").unwrap(),
+            Timestamp::Absolute(utc(1970, 1, 1, 0, 0, 0) - Duration::milliseconds(1))
+        );
+        assert!(Timestamp::from_str("epochms:x").is_err());
+
+        // A bare integer is still a relative timestamp
+        assert_eq!(
+            Timestamp::from_str("1648463013").unwrap(),
+            Timestamp::Relative(Duration::seconds(1648463013))
+        );
+    }
+
+    #[test]
+    fn timestamp_parse_countdown() {
+        assert_eq!(
+            Timestamp::from_str("deadline-300").unwrap(),
+            Timestamp::Countdown(Duration::minutes(5))
+        );
+        assert_eq!(
+            Timestamp::from_str("deadline-1h").unwrap(),
+            Timestamp::Countdown(Duration::hours(1))
+        );
+        assert_eq!(
+            Timestamp::from_str("deadline").unwrap(),
+            Timestamp::Countdown(Duration::zero())
+        );
+
+        assert!(Timestamp::from_str("deadline+10").is_err());
+        assert!(Timestamp::from_str("deadline--10").is_err());
+        assert!(Timestamp::from_str("deadline-x").is_err());
+    }
+
+    #[test]
+    fn messages_with_countdown() {
+        let data = r##"
+deadline-10m | countdown | T-10m
+deadline-60 | countdown | T-1m
+deadline | countdown | T-0
+5 | countdown | after
+deadline-2h | countdown | T-2h
+"##;
+        let t = utc(2022, 3, 28, 10, 0, 0);
+        let deadline = utc(2022, 3, 28, 11, 0, 0);
+        let options = ScriptOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        };
+
+        let msgs = load_messages(&options, Path::new("test.txt"), t, Cursor::new(data)).0;
+        assert_eq!(
+            msgs.iter().map(|m| m.timestamp.clone()).collect::<Vec<_>>(),
+            vec![
+                Timestamp::Absolute(deadline - Duration::minutes(10)),
+                Timestamp::Absolute(deadline - Duration::minutes(1)),
+                Timestamp::Absolute(deadline),
+                Timestamp::Absolute(deadline + Duration::seconds(5)),
+                Timestamp::Absolute(deadline - Duration::hours(2)),
+            ]
+        );
+
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            t,
+            Cursor::new(data),
+        );
+        assert_eq!(msgs.len(), 0);
+        assert_eq!(errors, 5);
+    }
+
+    #[test]
+    fn daily_occurrences_in_window() {
+        let tz = FixedOffset::east_opt(3600).unwrap();
+
+        assert_eq!(
+            daily_occurrences(
+                Duration::hours(8),
+                tz.with_ymd_and_hms(2022, 3, 27, 12, 0, 0).unwrap(),
+                tz.with_ymd_and_hms(2022, 3, 29, 12, 0, 0).unwrap(),
+                &tz
+            ),
+            vec![
+                tz.with_ymd_and_hms(2022, 3, 28, 8, 0, 0).unwrap(),
+                tz.with_ymd_and_hms(2022, 3, 29, 8, 0, 0).unwrap(),
+            ]
+        );
+
+        assert!(daily_occurrences(
+            Duration::hours(8),
+            tz.with_ymd_and_hms(2022, 3, 28, 8, 0, 0).unwrap(),
+            tz.with_ymd_and_hms(2022, 3, 28, 9, 0, 0).unwrap(),
+            &tz
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn daily_occurrences_in_zone() {
+        let start = utc(2022, 3, 28, 0, 0, 0);
+        let end = start + Duration::days(1);
+
+        assert_eq!(
+            parse_zone("Europe/London")
+                .unwrap()
+                .daily_occurrences(Duration::hours(8), start, end),
+            vec![utc(2022, 3, 28, 7, 0, 0)]
+        );
+        assert_eq!(
+            parse_zone("America/New_York").unwrap().daily_occurrences(
+                Duration::hours(8),
+                start,
+                end
+            ),
+            vec![utc(2022, 3, 28, 12, 0, 0)]
+        );
+
+        assert_eq!(parse_zone("local").unwrap(), Zone::Local);
+        assert!(parse_zone("Nowhere/Special").is_err());
+    }
+
+    #[test]
+    fn messages_in_zone() {
+        let data = "2022-03-28 10:00:00 | test_topic | msg";
+        let load = |zone| {
+            let options = ScriptOptions {
+                timezone: parse_zone(zone).unwrap(),
+                ..Default::default()
+            };
+            load_messages(&options, Path::new("test.txt"), now(), Cursor::new(data)).0[0]
+                .timestamp
+                .clone()
+        };
+
+        assert_eq!(
+            load("Asia/Tokyo"),
+            Timestamp::Absolute(utc(2022, 3, 28, 1, 0, 0))
+        );
+        assert_eq!(load("UTC"), Timestamp::Absolute(utc(2022, 3, 28, 10, 0, 0)));
+
+        let options = ScriptOptions {
+            timezone: parse_zone("Europe/London").unwrap(),
+            ..Default::default()
+        };
+        let data = "2022-03-27 01:30:00 | test_topic | msg";
+        let (messages, errors) =
+            load_messages(&options, Path::new("test.txt"), now(), Cursor::new(data));
+        assert!(messages.is_empty());
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn script_poll_daily() {
+        let t = now();
+
+        let message = Message::new(Timestamp::Daily(Duration::hours(8)), "test_topic", "msg");
+
+        let mut s = script_with_messages(vec![message], t - Duration::days(2));
+
+        let msgs = s.poll();
+        assert_eq!(msgs.len(), 2);
+        for m in &msgs {
+            let t = m.timestamp.scheduled_time().unwrap();
+            assert_eq!(
+                t.with_timezone(&Local).time(),
+                NaiveTime::from_hms_opt(8, 0, 0).unwrap()
+            );
+        }
+
+        assert_eq!(s.poll(), vec![]);
+    }
+
+    #[test]
+    fn script_poll_every_count() {
+        let t = now();
+
+        let message = Message::new(
+            Timestamp::Recurring {
+                next: t - Duration::seconds(10),
+                interval: Duration::seconds(1),
+                remaining: Some(3),
+            },
+            "test_topic",
+            "msg",
+        );
+
+        let mut s = script_with_messages(vec![message], t - Duration::seconds(20));
+
+        let msgs = s.poll();
+        assert_eq!(
+            msgs.iter().map(|m| m.timestamp.clone()).collect::<Vec<_>>(),
+            vec![
+                Timestamp::Absolute(t - Duration::seconds(10)),
+                Timestamp::Absolute(t - Duration::seconds(9)),
+                Timestamp::Absolute(t - Duration::seconds(8)),
+            ]
+        );
+
+        assert_eq!(s.poll(), vec![]);
+    }
+
+    #[test]
+    fn script_poll_every_unlimited() {
+        let t = now();
+
+        let message = Message::new(
+            Timestamp::Recurring {
+                next: t - Duration::milliseconds(10_500),
+                interval: Duration::seconds(1),
+                remaining: None,
+            },
+            "test_topic",
+            "msg",
+        );
+
+        let mut s = script_with_messages(vec![message], t - Duration::seconds(5));
+
+        // Only occurrences after the last poll are sent
+        assert_eq!(s.poll().len(), 5);
+    }
+
+    #[test]
+    fn timestamp_parse_anchored() {
+        assert_eq!(
+            Timestamp::from_str("@start+10").unwrap(),
+            Timestamp::Anchored {
+                anchor: "start".into(),
+                offset: Duration::seconds(10)
+            }
+        );
+        assert_eq!(
+            Timestamp::from_str("@start-2m").unwrap(),
+            Timestamp::Anchored {
+                anchor: "start".into(),
+                offset: Duration::minutes(-2)
+            }
+        );
+        assert_eq!(
+            Timestamp::from_str("@start").unwrap(),
+            Timestamp::Anchored {
+                anchor: "start".into(),
+                offset: Duration::zero()
+            }
+        );
+        assert!(Timestamp::from_str("@+10").is_err());
+        assert!(Timestamp::from_str("@start+x").is_err());
+    }
+
+    #[test]
+    fn messages_with_anchors() {
+        let data = r##"
+@start+10                           | root/user-1 | forward
+start=Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | start
+5                                   | root/user-1 | relative
+@start-5                            | root/user-1 | backward
+10                                  | root/user-1 | after anchored
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        assert_eq!(
+            msgs.iter().map(|m| m.timestamp.clone()).collect::<Vec<_>>(),
+            vec![
+                Timestamp::Absolute(t + Duration::seconds(10)),
+                Timestamp::Absolute(t),
+                Timestamp::Absolute(t + Duration::seconds(5)),
+                Timestamp::Absolute(t - Duration::seconds(5)),
+                Timestamp::Absolute(t + Duration::seconds(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_with_anchor_cycle() {
+        let data = r##"
+Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | ok
+a=@b+5                        | root/user-1 | a
+b=@a+5                        | root/user-1 | b
+@missing+5                    | root/user-1 | unknown anchor
+Mon, 28 Mar 2022 00:01:00 GMT | root/user-1 | also ok
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(
+            msgs.iter().map(|m| m.message.clone()).collect::<Vec<_>>(),
+            vec![Payload::from("ok"), Payload::from("also ok")]
+        );
+    }
+
+    #[test]
+    fn messages_with_deep_anchor_chain() {
+        // A forward reference to the end of a long chain of relative messages
+        let n = 30_000;
+        let mut data = String::from("@end+1                        | root/user-1 | after end\n");
+        data.push_str("Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | start\n");
+        data.push_str(&"1 | root/user-1 | m\n".repeat(n));
+        data.push_str("end=1 | root/user-1 | end\n");
+
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data.as_str()),
+        )
+        .0;
+        let end = utc(2022, 3, 28, 0, 0, 0) + Duration::seconds(n as i64 + 1);
+        assert_eq!(msgs.len(), n + 3);
+        assert_eq!(
+            msgs[0].timestamp,
+            Timestamp::Absolute(end + Duration::seconds(1))
+        );
+        assert_eq!(msgs[n + 2].timestamp, Timestamp::Absolute(end));
+
+        // Without the absolute start the chain leads back to the forward reference
+        let data = data.replacen("Mon, 28 Mar 2022 00:00:00 GMT", "1", 1);
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data.as_str()),
+        )
+        .0;
+        assert!(msgs.is_empty());
+    }
+
+    #[test]
+    fn messages_with_absolute_and_relative() {
+        let data = r##"
+Mon, 28 Mar 2022 00:00:00 GMT | root/user-1 | msg 1
+10                            | root/user-2 | msg 2
+20                            | root/user-1 | msg 3
+"##;
+        let c = Cursor::new(data);
+        let msgs = load_messages(&ScriptOptions::default(), Path::new("test.txt"), now(), c).0;
+        assert_eq!(msgs.len(), 3);
+    }
+
+    #[test]
+    fn messages_with_only_relative() {
+        let data = r##"
+ 5 | root/user-1 | msg 1
+10 | root/user-2 | msg 2
+20 | root/user-1 | msg 3
+"##;
+        let c = Cursor::new(data);
+        let msgs = load_messages(&ScriptOptions::default(), Path::new("test.txt"), now(), c).0;
+        assert_eq!(msgs.len(), 3);
+    }
+
+    #[test]
+    fn payload_decode() {
+        assert_eq!(Payload::from_str("hello").unwrap(), "hello");
+        assert_eq!(Payload::from_str("base64:aGVsbG8=").unwrap().0, b"hello");
+        assert_eq!(
+            Payload::from_str("hex:00ff7F").unwrap().0,
+            &[0x00, 0xff, 0x7f]
+        );
+        assert!(Payload::from_str("hex:").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn payload_decode_invalid() {
+        assert!(Payload::from_str("base64:not base64!").is_err());
+        assert!(Payload::from_str("hex:abc").is_err());
+        assert!(Payload::from_str("hex:zz").is_err());
+    }
+
+    #[test]
+    fn messages_with_encoded_payloads() {
+        let data = r##"
+0 | root/user-1 | hex:0102
+0 | root/user-1 | hex:xyz
+0 | root/user-1 | base64:AQI=
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].message.0, &[1, 2]);
+        assert_eq!(msgs[1].message.0, &[1, 2]);
+    }
+
+    #[test]
+    fn messages_compressed() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let data = r##"
+0 | root/user-1 | msg 1
+0 | root/user-2 | msg 2
+"##;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            GzDecoder::new(Cursor::new(compressed)),
+        )
+        .0;
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1].message, "msg 2");
+    }
+
+    #[test]
+    fn script_file_detection() {
+        let dir = Path::new("/scripts");
+        let ScriptOptions {
+            script_globs: globs,
+            ignore_patterns: ignore,
+            ..
+        } = ScriptOptions::default();
+        let is_script_file = |path| is_script_file(dir, &globs, &ignore, Path::new(path));
+
+        assert!(is_script_file("/scripts/b.txt"));
+        assert!(is_script_file("/scripts/a/b.txt"));
+        assert!(is_script_file("/scripts/a/b.txt.gz"));
+        assert!(!is_script_file("/scripts/a/b.gz"));
+        assert!(!is_script_file("/scripts/a/b.csv"));
+        assert!(!is_script_file("/scripts/a/b"));
+        assert!(!is_script_file("/other/a/b.txt"));
+    }
+
+    #[test]
+    fn editor_files_ignored() {
+        let dir = Path::new("/scripts");
+        let ScriptOptions {
+            ignore_patterns: ignore,
+            ..
+        } = ScriptOptions::default();
+        let globs = vec!["**/*".to_string()];
+        let is_script_file = |path| is_script_file(dir, &globs, &ignore, Path::new(path));
+
+        assert!(is_script_file("/scripts/a/b.txt"));
+        assert!(!is_script_file("/scripts/a/.b.txt.swp"));
+        assert!(!is_script_file("/scripts/a/b.txt.swp"));
+        assert!(!is_script_file("/scripts/a/b.txt~"));
+        assert!(!is_script_file("/scripts/a/#b.txt#"));
+        assert!(!is_script_file("/scripts/a/.b.txt"));
+        assert!(!is_script_file("/scripts/.a/b.txt"));
+
+        let dir = test_dir();
+        fs::write(dir.path().join("b.txt"), "0 | test_topic | msg 1").unwrap();
+        fs::write(dir.path().join("b.txt.swp"), "0 | test_topic | msg 2").unwrap();
+        fs::write(dir.path().join("b.txt~"), "0 | test_topic | msg 3").unwrap();
+
+        assert_eq!(
+            find_script_files(dir.path(), &globs, &ignore).unwrap(),
+            vec![dir.path().join("b.txt")]
+        );
+    }
+
+    #[test]
+    fn script_from_url() {
+        let body = Arc::new(Mutex::new(Ok("1h | test_topic | msg 1".to_string())));
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let options = ScriptOptions {
+            fetcher: {
+                let (body, fetched) = (body.clone(), fetched.clone());
+                Fetcher::new(move |url| {
+                    fetched.lock().unwrap().push(url.to_string());
+                    body.lock()
+                        .unwrap()
+                        .as_ref()
+                        .cloned()
+                        .map_err(|e: &String| anyhow!("{}", e))
+                })
+            },
+            ..Default::default()
+        };
+        let url = Path::new("https://example.com/schedules/lights.txt");
+
+        let mut s = Script::new(url, options).unwrap();
+        assert_eq!(
+            *fetched.lock().unwrap(),
+            vec!["https://example.com/schedules/lights.txt"]
+        );
+        assert_eq!(s.messages().count(), 1);
+        let scheduled = s.messages().next().unwrap().timestamp.clone();
+
+        // An unchanged script keeps its schedule
+        s.reload().unwrap();
+        assert_eq!(s.messages().next().unwrap().timestamp, scheduled);
+
+        *body.lock().unwrap() = Ok("1h | test_topic | msg 1\n2h | test_topic | msg 2".into());
+        s.reload().unwrap();
+        assert_eq!(s.messages().count(), 2);
+
+        // The previously loaded script is kept if it cannot be fetched
+        *body.lock().unwrap() = Err("503 Service Unavailable".into());
+        assert!(s.reload().is_err());
+        assert_eq!(s.messages().count(), 2);
+        assert_eq!(fetched.lock().unwrap().len(), 4);
+
+        assert_eq!(
+            script_url(Path::new("http://example.com/a.txt")),
+            Some("http://example.com/a.txt")
+        );
+        assert_eq!(script_url(Path::new("/scripts")), None);
+        assert_eq!(script_url(Path::new("httpx/scripts")), None);
+    }
+
+    #[test]
+    fn equal_timestamps_deterministic_order() {
+        let t = (now() + Duration::hours(1)).to_rfc3339();
+        let row = |topic: &str, payload: &str| format!("{} | {} | {}\n", t, topic, payload);
+
+        let upcoming = |files: &[(&str, String)]| {
+            let dir = test_dir();
+            for (name, contents) in files {
+                fs::write(dir.path().join(name), contents).unwrap();
+            }
+            let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+            s.upcoming(now() + Duration::hours(2), 1)
+                .iter()
+                .map(|m| format!("{} {}", m.topic, m.message))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec!["a 1", "a 2", "b 1", "c 1", "c 1"];
+
+        assert_eq!(
+            upcoming(&[
+                ("a.txt", row("c", "1") + &row("b", "1") + &row("a", "2")),
+                ("b.txt", row("a", "1") + &row("c", "1")),
+            ]),
+            expected
+        );
+        assert_eq!(
+            upcoming(&[
+                ("a.txt", row("a", "1") + &row("c", "1")),
+                ("b.txt", row("c", "1") + &row("a", "2") + &row("b", "1")),
+            ]),
+            expected
+        );
+    }
+
+    #[test]
+    fn script_file_extension_case_insensitive() {
+        let dir = test_dir();
+        fs::write(dir.path().join("a.TXT"), "0 | test_topic | msg 1").unwrap();
+        fs::write(dir.path().join("b.Txt"), "0 | test_topic | msg 2").unwrap();
+        fs::write(dir.path().join("c.CSV"), "0 | test_topic | msg 3").unwrap();
+
+        let options = ScriptOptions::default();
+
+        assert!(is_script_file(
+            dir.path(),
+            &options.script_globs,
+            &options.ignore_patterns,
+            &dir.path().join("a.TXT")
+        ));
+        assert!(is_script_file(
+            dir.path(),
+            &options.script_globs,
+            &options.ignore_patterns,
+            &dir.path().join("d.TXT.GZ")
+        ));
+        assert!(!is_script_file(
+            dir.path(),
+            &options.script_globs,
+            &options.ignore_patterns,
+            &dir.path().join("c.CSV")
+        ));
+        assert!(is_compressed(Path::new("d.TXT.GZ")));
+
+        let s = Script::new(dir.path(), options).unwrap();
+        assert_eq!(s.messages().count(), 2);
+    }
+
+    #[test]
+    fn script_file_custom_glob() {
+        let dir = test_dir();
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/b.sched"), "0 | test_topic | msg 1").unwrap();
+        fs::write(dir.path().join("c.sched"), "0 | test_topic | msg 2").unwrap();
+        fs::write(dir.path().join("d.txt"), "0 | test_topic | msg 3").unwrap();
+
+        let options = ScriptOptions {
+            script_globs: vec!["**/*.sched".to_string()],
+            ..Default::default()
+        };
+
+        assert!(is_script_file(
+            dir.path(),
+            &options.script_globs,
+            &options.ignore_patterns,
+            &dir.path().join("a/b.sched")
+        ));
+        assert!(!is_script_file(
+            dir.path(),
+            &options.script_globs,
+            &options.ignore_patterns,
+            &dir.path().join("d.txt")
+        ));
+
+        let s = Script::new(dir.path(), options).unwrap();
+        assert_eq!(s.messages().count(), 2);
+
+        assert!(parse_script_glob("**/*.sched").is_ok());
+        assert!(parse_script_glob("**/[.sched").is_err());
+    }
+
+    #[test]
+    fn messages_with_retain() {
+        let data = r##"
+0 | root/user-1 | msg 1
+0 | root/user-1 | msg 2 | true
+0 | root/user-1 | msg 3 | false
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(
+            msgs.iter().map(|m| m.retain).collect::<Vec<_>>(),
+            vec![false, true, false]
+        );
+    }
+
+    #[test]
+    fn messages_with_comments() {
+        let data = r##"
+# A comment
+0 | root/user-1 | msg 1
+  # An indented comment | with | delimiters
+0 | root/user-1 | # not a comment
+"##;
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        );
+        assert_eq!(errors, 0);
+        assert_eq!(
+            msgs.iter().map(|m| m.message.clone()).collect::<Vec<_>>(),
+            vec!["msg 1", "# not a comment"]
+        );
+    }
+
+    #[test]
+    fn messages_relative_mode() {
+        let data = r##"
+10 | test_topic | msg 1
+20 | test_topic | msg 2
+every:30s | test_topic | msg 3
+5 | test_topic | msg 4
+"##;
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        let times = |relative_mode| {
+            let options = ScriptOptions {
+                relative_mode,
+                ..Default::default()
+            };
+            load_messages(&options, Path::new("test.txt"), t, Cursor::new(data))
+                .0
+                .iter()
+                .map(|m| m.timestamp.scheduled_time().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            times(RelativeMode::Cumulative),
+            vec![
+                t + Duration::seconds(10),
+                t + Duration::seconds(30),
+                t + Duration::seconds(60),
+                t + Duration::seconds(35),
+            ]
+        );
+        assert_eq!(
+            times(RelativeMode::Independent),
+            vec![
+                t + Duration::seconds(10),
+                t + Duration::seconds(20),
+                t + Duration::seconds(30),
+                t + Duration::seconds(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_with_qos() {
+        let data = r##"
+0 | root/user-1 | msg 1
+0 | root/user-1 | msg 2 | true  | | 0 | | | | 2
+0 | root/user-1 | msg 3 | false | | 0 | | | | 0
+0 | root/user-1 | msg 4 | false | | 0 | | | | 3
+"##;
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        );
+        assert_eq!(
+            msgs.iter().map(|m| (m.retain, m.qos)).collect::<Vec<_>>(),
+            vec![(false, None), (true, Some(2)), (false, Some(0))]
+        );
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn messages_with_condition() {
+        let data = r##"
+0 | root/user-1 | msg 1 | false | | 0 | | | | | condition:flags/enabled
+0 | root/user-1 | msg 2 | false | | 0 | | | | | condition:flags/enabled | root/else
+0 | root/user-1 | msg 3 | false | | 0 | | | | | flags/enabled
+"##;
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        );
+        assert_eq!(
+            msgs.iter()
+                .map(|m| (m.condition.clone(), m.else_topic.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(PathBuf::from("flags/enabled")), None),
+                (Some(PathBuf::from("flags/enabled")), Some("root/else")),
+            ]
+        );
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn message_from_json() {
+        let m: Message = serde_json::from_str(
+            r#"{"timestamp": "10", "topic": "a/b", "message": "hi", "qos": 2, "retain": true}"#,
+        )
+        .unwrap();
+        assert_eq!(m.timestamp, Timestamp::Relative(Duration::seconds(10)));
+        assert_eq!(m.qos, Some(2));
+        assert!(m.retain);
+
+        let m: Message =
+            serde_json::from_str(r#"{"timestamp": "10", "topic": "a/b", "message": "hi"}"#)
+                .unwrap();
+        assert_eq!(m.qos, None);
+        assert!(!m.retain);
+
+        assert!(serde_json::from_str::<Message>(
+            r#"{"timestamp": "10", "topic": "a/b", "message": "hi", "qos": 3}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn messages_with_control_characters_in_topic() {
+        assert!(validate_topic("root/user-1").is_ok());
+        assert!(validate_topic("root/ünïcödé").is_ok());
+        assert!(validate_topic("root/\0user").is_err());
+        assert!(validate_topic("root/\tuser").is_err());
+
+        let data =
+            "0 | root/user-1 | msg 1\n0 | root/\0user-1 | msg 2\n0 | root/\x1buser-1 | msg 3";
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        );
+        assert_eq!(
+            msgs.iter().map(|m| m.topic.as_str()).collect::<Vec<_>>(),
+            vec!["root/user-1"]
+        );
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn messages_with_empty_payload() {
+        let data = r##"
+0 | root/user-1 |
+0 | root/user-1 | | true
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs.iter().all(|m| m.message.0.is_empty()));
+        assert!(!msgs[0].retain);
+        assert!(msgs[1].retain);
+    }
+
+    #[test]
+    fn script_poll() {
+        let data = r##"
+1 | test_topic | msg 1
+1 | test_topic | msg 2
 1 | test_topic | msg 3
 1 | test_topic | msg 4
 1 | test_topic | msg 5
 "##;
 
-        let mut s = Script {
-            source_dir: PathBuf::new(),
-            source_file_delimiter: b'|',
-            messages: load_messages(b'|', Cursor::new(data)),
-            last_poll_time: now(),
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        let (clock, time) = manual_clock(t);
+
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            t,
+            Cursor::new(data),
+        )
+        .0;
+        let mut s = script_with_messages(msgs.clone(), t);
+        s.clock = clock;
+
+        let advance_to = |ms| *time.lock().unwrap() = t + Duration::milliseconds(ms);
+
+        advance_to(10);
+        assert_eq!(s.poll(), vec![]);
+
+        advance_to(1010);
+        assert_eq!(s.poll(), vec![msgs[0].clone()]);
+
+        advance_to(3010);
+        assert_eq!(s.poll(), vec![msgs[1].clone(), msgs[2].clone()]);
+
+        advance_to(3510);
+        assert_eq!(s.poll(), vec![]);
+
+        advance_to(4010);
+        assert_eq!(s.poll(), vec![msgs[3].clone()]);
+
+        advance_to(6010);
+        assert_eq!(s.poll(), vec![msgs[4].clone()]);
+    }
+
+    #[test]
+    fn reload_timestamps() {
+        assert_eq!(
+            "reload+30".parse::<Timestamp>().unwrap(),
+            Timestamp::AfterReload(Duration::seconds(30))
+        );
+        assert_eq!(
+            "reload+5m".parse::<Timestamp>().unwrap(),
+            Timestamp::AfterReload(Duration::minutes(5))
+        );
+        assert_eq!(
+            "reload".parse::<Timestamp>().unwrap(),
+            Timestamp::AfterReload(Duration::zero())
+        );
+        assert!("reload-30".parse::<Timestamp>().is_err());
+        assert!("reload+x".parse::<Timestamp>().is_err());
+
+        let dir = test_dir();
+        fs::write(
+            dir.path().join("a.txt"),
+            "reload+30 | state | republish\n1h | test_topic | msg",
+        )
+        .unwrap();
+
+        let t = utc(2022, 3, 28, 10, 0, 0);
+        let (clock, time) = manual_clock(t);
+        let mut s = Script::with_clock(dir.path(), ScriptOptions::default(), clock).unwrap();
+        let poll_at = |s: &mut Script, seconds| {
+            *time.lock().unwrap() = t + Duration::seconds(seconds);
+            s.poll()
+                .iter()
+                .map(|m| m.timestamp.scheduled_time().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(poll_at(&mut s, 20).is_empty());
+        assert_eq!(poll_at(&mut s, 40), vec![t + Duration::seconds(30)]);
+        assert!(poll_at(&mut s, 60).is_empty());
+
+        // Reloading re-anchors the message, although the file has not changed
+        *time.lock().unwrap() = t + Duration::seconds(100);
+        s.reload().unwrap();
+        assert!(!s.is_exhausted());
+        assert!(poll_at(&mut s, 120).is_empty());
+        assert_eq!(poll_at(&mut s, 130), vec![t + Duration::seconds(130)]);
+        assert!(poll_at(&mut s, 600).is_empty());
+    }
+
+    #[test]
+    fn cancel_message() {
+        let dir = test_dir();
+        let t = utc(2022, 3, 28, 10, 0, 0);
+        let at = |seconds| t + Duration::seconds(seconds);
+        let contents = format!(
+            "{} | a | msg 1\n{} | b | msg 2\n{} | a | msg 3\n",
+            at(10).to_rfc3339(),
+            at(10).to_rfc3339(),
+            at(20).to_rfc3339()
+        );
+        fs::write(dir.path().join("a.txt"), &contents).unwrap();
+
+        let (clock, time) = manual_clock(t);
+        let mut s = Script::with_clock(dir.path(), ScriptOptions::default(), clock).unwrap();
+        assert_eq!(s.scheduled_count(), 3);
+
+        assert_eq!(s.cancel("a", at(10)), Cancellation::Cancelled(1));
+        assert_eq!(s.cancel("a", at(15)), Cancellation::NotFound);
+        assert_eq!(s.cancel("c", at(10)), Cancellation::NotFound);
+        assert_eq!(s.scheduled_count(), 2);
+
+        // The cancellation still applies after the file is loaded again
+        fs::write(dir.path().join("a.txt"), contents + "\n").unwrap();
+        s.reload().unwrap();
+        assert_eq!(s.scheduled_count(), 2);
+
+        *time.lock().unwrap() = at(15);
+        let msgs = s.poll();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].message, "msg 2");
+
+        assert_eq!(s.cancel("b", at(10)), Cancellation::AlreadySent);
+        assert_eq!(s.cancel("a", at(20)), Cancellation::Cancelled(1));
+        assert!(s.is_exhausted());
+    }
+
+    #[test]
+    fn script_loop_daily() {
+        let dir = test_dir();
+        fs::write(dir.path().join("a.txt"), "1h | test_topic | msg").unwrap();
+
+        let day = NaiveDate::from_ymd_opt(2022, 3, 28).unwrap();
+        let midnight = start_of_day(day, &Local).unwrap();
+        let next_midnight = start_of_day(day.succ_opt().unwrap(), &Local).unwrap();
+
+        let (clock, time) = manual_clock(midnight + Duration::minutes(30));
+        let options = ScriptOptions {
+            loop_period: Some(LoopPeriod::Daily),
+            ..Default::default()
+        };
+        let mut s = Script::with_clock(dir.path(), options, clock).unwrap();
+
+        let sent_at = |msgs: Vec<Message>| {
+            msgs.iter()
+                .map(|m| m.timestamp.scheduled_time().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        *time.lock().unwrap() = midnight + Duration::minutes(90);
+        assert_eq!(sent_at(s.poll()), vec![midnight + Duration::hours(1)]);
+
+        *time.lock().unwrap() = next_midnight + Duration::minutes(30);
+        assert!(s.poll().is_empty());
+
+        *time.lock().unwrap() = next_midnight + Duration::minutes(90);
+        assert_eq!(sent_at(s.poll()), vec![next_midnight + Duration::hours(1)]);
+
+        // Periods that are skipped entirely are still replayed
+        let later = start_of_day(day + Duration::days(3), &Local).unwrap();
+        *time.lock().unwrap() = later + Duration::minutes(30);
+        assert_eq!(
+            sent_at(s.poll()),
+            vec![start_of_day(day + Duration::days(2), &Local).unwrap() + Duration::hours(1),]
+        );
+    }
+
+    #[test]
+    fn script_on_connect() {
+        let data = r##"
+on-connect | state/light | on   | true
+1          | test_topic  | msg 1
+on-connect | state/fan   | off  | true
+1          | test_topic  | msg 2
+"##;
+
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            t,
+            Cursor::new(data),
+        )
+        .0;
+        let s = script_with_messages(msgs, t);
+
+        assert_eq!(
+            s.on_connect_messages()
+                .iter()
+                .map(|m| m.topic.as_str())
+                .collect::<Vec<_>>(),
+            vec!["state/light", "state/fan"]
+        );
+        assert_eq!(s.scheduled_count(), 2);
+
+        // On connect messages do not affect the time of the messages that follow them
+        assert_eq!(
+            s.upcoming(t + Duration::days(1), 1)
+                .iter()
+                .map(|m| m.timestamp.scheduled_time().unwrap())
+                .collect::<Vec<_>>(),
+            vec![t + Duration::seconds(1), t + Duration::seconds(2)]
+        );
+    }
+
+    #[test]
+    fn strict_start() {
+        let options = || ScriptOptions {
+            strict_start: true,
+            ..Default::default()
+        };
+
+        // Empty directory
+        let dir = test_dir();
+        assert!(Script::new(dir.path(), ScriptOptions::default()).is_ok());
+        assert!(Script::new(dir.path(), options()).is_err());
+
+        // Invalid message
+        fs::write(
+            dir.path().join("a.txt"),
+            "1h | test_topic | msg 1\nnot a time | test_topic | msg 2",
+        )
+        .unwrap();
+        assert!(Script::new(dir.path(), ScriptOptions::default()).is_ok());
+        assert!(Script::new(dir.path(), options()).is_err());
+
+        // File that is not valid UTF-8
+        fs::write(dir.path().join("a.txt"), "1h | test_topic | msg 1").unwrap();
+        fs::write(dir.path().join("b.txt"), [0xff, 0xfe]).unwrap();
+        assert!(Script::new(dir.path(), options()).is_err());
+
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        assert!(Script::new(dir.path(), options()).is_ok());
+    }
+
+    #[test]
+    fn file_being_written_is_retried() {
+        let dir = test_dir();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "1h | test_topic | msg 1").unwrap();
+
+        let t = now();
+        let (clock, time) = manual_clock(t);
+        let options = ScriptOptions {
+            settle_time: std::time::Duration::from_secs(2),
+            ..Default::default()
+        };
+        let mut s = Script::with_clock(dir.path(), options, clock).unwrap();
+        assert!(s.is_empty());
+        assert!(s.has_unsettled_files());
+
+        *time.lock().unwrap() = t + Duration::seconds(5);
+        s.reload().unwrap();
+        assert_eq!(s.messages().count(), 1);
+        assert!(!s.has_unsettled_files());
+
+        // A file that is being rewritten keeps its previously loaded messages until it settles
+        fs::write(&path, "1h | test_topic | msg 1\n1h | test_topic").unwrap();
+        *time.lock().unwrap() = now();
+        s.reload().unwrap();
+        assert_eq!(s.messages().next().unwrap().message, "msg 1");
+        assert!(s.has_unsettled_files());
+
+        fs::write(&path, "1h | test_topic | msg 1\n1h | test_topic | msg 2").unwrap();
+        *time.lock().unwrap() = now() + Duration::seconds(5);
+        s.reload().unwrap();
+        assert_eq!(s.messages().count(), 2);
+        assert!(!s.has_unsettled_files());
+    }
+
+    #[test]
+    fn script_poll_persists_state() {
+        let dir = test_dir();
+        let state_file = dir.path().join("state");
+
+        let mut s = Script::new(
+            dir.path(),
+            ScriptOptions {
+                state_file: Some(state_file.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        s.poll();
+        let first = load_state(&state_file).unwrap();
+        assert_eq!(first, s.last_poll_time);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        s.poll();
+        let second = load_state(&state_file).unwrap();
+        assert_eq!(second, s.last_poll_time);
+        assert!(second > first);
+
+        // The state is written to a temporary file that then replaces the state file
+        assert!(!dir.path().join("state.tmp").exists());
+    }
+
+    #[test]
+    fn script_restores_state() {
+        let dir = test_dir();
+        let state_file = dir.path().join("state");
+
+        let restored = now() - Duration::seconds(5);
+        fs::write(&state_file, restored.to_rfc3339()).unwrap();
+        fs::write(
+            dir.path().join("script.txt"),
+            format!(
+                "{} | test_topic | msg 1",
+                (now() - Duration::seconds(2)).to_rfc3339()
+            ),
+        )
+        .unwrap();
+
+        let mut s = Script::new(
+            dir.path(),
+            ScriptOptions {
+                state_file: Some(state_file.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(s.last_poll_time, restored);
+        let expected = vec![s.messages().next().unwrap().clone()];
+        assert_eq!(s.poll(), expected);
+    }
+
+    #[test]
+    fn script_corrupt_state_falls_back_to_now() {
+        let dir = test_dir();
+        let state_file = dir.path().join("state");
+        fs::write(&state_file, "not a timestamp").unwrap();
+
+        let before = now();
+        let s = Script::new(
+            dir.path(),
+            ScriptOptions {
+                state_file: Some(state_file.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(s.last_poll_time >= before);
+
+        let s = Script::new(
+            dir.path(),
+            ScriptOptions {
+                state_file: Some(dir.path().join("missing")),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(s.last_poll_time >= before);
+    }
+
+    #[test]
+    fn script_reload_only_changed_files() {
+        let dir = test_dir();
+        fs::write(dir.path().join("a.txt"), "0 | topic_a | msg a").unwrap();
+        fs::write(dir.path().join("b.txt"), "0 | topic_b | msg b").unwrap();
+
+        let mut s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let a = s.files[&dir.path().join("a.txt")].messages.clone();
+        let b = s.files[&dir.path().join("b.txt")].messages.clone();
+
+        // Relative timestamps are resolved at load time, so a file that is parsed again gets new
+        // timestamps
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(dir.path().join("b.txt"), "0 | topic_b | msg b edited").unwrap();
+        s.reload().unwrap();
+
+        assert_eq!(s.files[&dir.path().join("a.txt")].messages, a);
+        let b_edited = &s.files[&dir.path().join("b.txt")].messages;
+        assert_eq!(b_edited[0].message, "msg b edited");
+        assert!(b_edited[0].timestamp.scheduled_time() > b[0].timestamp.scheduled_time());
+    }
+
+    #[test]
+    fn script_file_message_counts() {
+        let dir = test_dir();
+        fs::write(
+            dir.path().join("a.txt"),
+            "10 | topic_a | msg a 1\n10 | topic_a,topic_b | msg a 2\nx | topic_a | invalid",
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.txt"), "x | topic_b | invalid").unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        assert_eq!(
+            s.file_message_counts(),
+            vec![
+                (dir.path().join("a.txt").as_path(), 3),
+                (dir.path().join("b.txt").as_path(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn script_include() {
+        let dir = test_dir();
+        fs::create_dir(dir.path().join("shared")).unwrap();
+        fs::write(
+            dir.path().join("a.txt"),
+            concat!(
+                "10 | topic_a | msg a\n",
+                "include: shared/common.inc\n",
+                "  include:missing.inc\n",
+                "5 | topic_a | msg a 2",
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("shared/common.inc"),
+            "20 | {{path}} | msg common",
+        )
+        .unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let messages = &s.files[&dir.path().join("a.txt")].messages;
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| (m.topic.as_str(), m.message.to_string_lossy()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("topic_a", "msg a".to_string()),
+                ("topic_a", "msg a 2".to_string()),
+                ("shared/common", "msg common".to_string()),
+            ]
+        );
+        // Included files are loaded as files of their own, so relative timestamps start again
+        assert_eq!(
+            messages[1].timestamp.scheduled_time().unwrap()
+                - messages[0].timestamp.scheduled_time().unwrap(),
+            Duration::seconds(5)
+        );
+        assert_eq!(s.errors, 1);
+    }
+
+    #[test]
+    fn script_include_cycle() {
+        let dir = test_dir();
+        fs::write(
+            dir.path().join("a.txt"),
+            "0 | topic_a | msg a\ninclude:b.inc\ninclude:a.txt",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("b.inc"),
+            "0 | topic_b | msg b\ninclude:a.txt",
+        )
+        .unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        assert_eq!(
+            s.messages().map(|m| m.topic.as_str()).collect::<Vec<_>>(),
+            vec!["topic_a", "topic_b"]
+        );
+        assert_eq!(s.errors, 2);
+    }
+
+    #[test]
+    fn script_max_files() {
+        let dir = test_dir();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.path().join(name), "10 | test_topic | msg").unwrap();
+        }
+
+        let mut paths = vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")];
+        assert_eq!(limit_files(&mut paths, None), None);
+        assert_eq!(limit_files(&mut paths, Some(2)), None);
+        assert_eq!(
+            limit_files(&mut paths, Some(1)),
+            Some("Found 2 script files, only loading the first 1".to_string())
+        );
+        assert_eq!(paths, vec![PathBuf::from("a.txt")]);
+
+        let options = ScriptOptions {
+            max_files: Some(2),
+            ..Default::default()
+        };
+        let s = Script::new(dir.path(), options).unwrap();
+        assert_eq!(
+            s.file_message_counts(),
+            vec![
+                (dir.path().join("a.txt").as_path(), 1),
+                (dir.path().join("b.txt").as_path(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn script_loaded_message_count() {
+        let dir = test_dir();
+        fs::write(
+            dir.path().join("a.txt"),
+            "10 | topic_a | msg a 1\n10 | topic_a | msg a 2",
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.txt"), "10 | topic_b | msg b").unwrap();
+
+        for log_loaded_messages in [false, true] {
+            let options = ScriptOptions {
+                log_loaded_messages,
+                ..Default::default()
+            };
+            let s = Script::new(dir.path(), options).unwrap();
+            assert_eq!(s.log_loaded_messages(), 3);
+        }
+    }
+
+    #[test]
+    fn script_reload_removed_file() {
+        let dir = test_dir();
+        fs::write(
+            dir.path().join("a.txt"),
+            "10 | topic_a | msg a 1\n10 | topic_a | msg a 2",
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.txt"), "10 | topic_b | msg b").unwrap();
+
+        let mut s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        assert_eq!(s.scheduled_count(), 3);
+        let a = s.files[&dir.path().join("a.txt")].messages.clone();
+
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        s.reload().unwrap();
+
+        assert_eq!(s.scheduled_count(), 2);
+        assert_eq!(s.messages().cloned().collect::<Vec<_>>(), a);
+    }
+
+    #[test]
+    fn script_past_messages() {
+        let data = r##"
+Mon, 28 Mar 2022 00:00:00 GMT | test_topic | msg 1
+10                            | test_topic | msg 2
+every:10s                     | test_topic | msg 3
+"##;
+        let mut msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        msgs.extend(
+            load_messages(
+                &ScriptOptions::default(),
+                Path::new("test.txt"),
+                now(),
+                Cursor::new("10 | test_topic | msg 4"),
+            )
+            .0,
+        );
+
+        let s = script_with_messages(msgs, now());
+        let past = s.past_messages();
+        assert_eq!(past.len(), 2);
+        assert_eq!(past[0].message, "msg 1");
+        assert_eq!(past[1].message, "msg 2");
+    }
+
+    #[test]
+    fn script_upcoming() {
+        let t = now();
+
+        let mut msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(
+                r##"
+10 | test_topic | msg 1
+every:1m | test_topic | msg 2
+7200 | test_topic | msg 3
+"##,
+            ),
+        )
+        .0;
+        msgs[0].timestamp = Timestamp::Absolute(t + Duration::seconds(10));
+        msgs[1].timestamp = Timestamp::Recurring {
+            next: t + Duration::minutes(1),
+            interval: Duration::minutes(1),
+            remaining: None,
+        };
+        msgs[2].timestamp = Timestamp::Absolute(t + Duration::hours(2));
+
+        let s = script_with_messages(msgs, t);
+
+        let upcoming = s.upcoming(t + Duration::hours(1), 3);
+        assert_eq!(
+            upcoming
+                .iter()
+                .map(|m| m.timestamp.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Timestamp::Absolute(t + Duration::seconds(10)),
+                Timestamp::Absolute(t + Duration::minutes(1)),
+                Timestamp::Absolute(t + Duration::minutes(2)),
+                Timestamp::Absolute(t + Duration::minutes(3)),
+            ]
+        );
+
+        // Listing upcoming messages does not advance the schedule
+        assert_eq!(s.upcoming(t + Duration::hours(1), 3), upcoming);
+    }
+
+    #[test]
+    fn path_topic_templates() {
+        let path = Path::new("devices/kitchen/light.txt");
+        assert_eq!(
+            path_topic(DEFAULT_PATH_TEMPLATE, path),
+            "devices/kitchen/light"
+        );
+        assert_eq!(path_topic("{name}", path), "light");
+        assert_eq!(path_topic("home/{dir}", path), "home/devices/kitchen");
+        assert_eq!(
+            path_topic(DEFAULT_PATH_TEMPLATE, Path::new("light.txt")),
+            "light"
+        );
+    }
+
+    #[test]
+    fn script_path_placeholder() {
+        let dir = test_dir();
+        fs::create_dir_all(dir.path().join("devices/kitchen")).unwrap();
+        fs::write(
+            dir.path().join("devices/kitchen/light.txt"),
+            "0 | {{path}}/set | on",
+        )
+        .unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let msgs: Vec<_> = s.messages().collect();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].topic, "devices/kitchen/light/set");
+    }
+
+    #[test]
+    fn script_collisions() {
+        let data = r##"
+Mon, 28 Mar 2022 00:00:00 GMT | test_topic  | msg 1
+Mon, 28 Mar 2022 00:00:00 GMT | test_topic  | msg 2
+Mon, 28 Mar 2022 00:00:00 GMT | other_topic | msg 3
+Mon, 28 Mar 2022 00:00:01 GMT | test_topic  | msg 4
+Mon, 28 Mar 2022 00:00:01 GMT | test_topic  | msg 4
+"##;
+        let dir = test_dir();
+        fs::write(dir.path().join("a.txt"), data).unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let collisions = s.collisions();
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].0.message, "msg 1");
+        assert_eq!(collisions[0].1.message, "msg 2");
+
+        let options = ScriptOptions {
+            strict: true,
+            ..Default::default()
         };
+        assert!(Script::new(dir.path(), options.clone()).is_err());
+
+        // A reload that introduces a collision keeps the previous script
+        fs::write(dir.path().join("a.txt"), "0 | test_topic | msg 1").unwrap();
+        let mut s = Script::new(dir.path(), options).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(dir.path().join("a.txt"), data).unwrap();
+        assert!(s.reload().is_err());
+        assert_eq!(s.messages().count(), 1);
+    }
+
+    #[test]
+    fn script_reload_mid_window() {
+        let dir = test_dir();
+        let mut s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+
+        let t = now();
+        s.last_poll_time = t - Duration::seconds(10);
 
-        // t =   10
+        // Added after the last poll, scheduled both before and after it
+        fs::write(
+            dir.path().join("a.txt"),
+            format!(
+                "{} | test_topic | before\n{} | test_topic | after",
+                (t - Duration::seconds(20)).to_rfc3339(),
+                (t - Duration::seconds(5)).to_rfc3339(),
+            ),
+        )
+        .unwrap();
+        s.reload().unwrap();
+
+        let msgs = s.poll();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].message, "after");
+
+        // Editing the file does not send messages that were already sent again
         std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(
+            dir.path().join("a.txt"),
+            format!(
+                "{} | test_topic | after edited",
+                (t - Duration::seconds(5)).to_rfc3339(),
+            ),
+        )
+        .unwrap();
+        s.reload().unwrap();
         assert_eq!(s.poll(), vec![]);
+    }
+
+    #[test]
+    fn script_reload_relative_mid_window() {
+        let dir = test_dir();
+        fs::write(dir.path().join("a.txt"), "0 | test_topic | msg 1").unwrap();
+        let mut s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
 
-        // t = 1010
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        assert_eq!(s.poll(), vec![s.messages[0].clone()]);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert_eq!(s.poll().len(), 1);
 
-        // t = 3010
-        std::thread::sleep(std::time::Duration::from_secs(2));
-        assert_eq!(s.poll(), vec![s.messages[1].clone(), s.messages[2].clone()]);
+        // A file that is parsed again has its relative messages rescheduled from the reload time
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(dir.path().join("a.txt"), "0 | test_topic | msg 1 edited").unwrap();
+        s.reload().unwrap();
 
-        // t = 3510
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let msgs = s.poll();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].message, "msg 1 edited");
         assert_eq!(s.poll(), vec![]);
+    }
+
+    #[test]
+    fn script_exhausted() {
+        let t = now();
+
+        let message = |timestamp| Message::new(timestamp, "test_topic", "msg");
+
+        let s = script_with_messages(vec![], t);
+        assert!(s.is_exhausted());
+        assert!(!s.is_unbounded());
+
+        let s = script_with_messages(
+            vec![
+                message(Timestamp::Absolute(t - Duration::seconds(10))),
+                message(Timestamp::Absolute(t)),
+            ],
+            t,
+        );
+        assert!(s.is_exhausted());
+
+        let mut s = script_with_messages(
+            vec![
+                message(Timestamp::Absolute(t - Duration::seconds(10))),
+                message(Timestamp::Recurring {
+                    next: t - Duration::seconds(2),
+                    interval: Duration::seconds(1),
+                    remaining: Some(2),
+                }),
+            ],
+            t - Duration::seconds(5),
+        );
+        assert!(!s.is_exhausted());
+        assert!(!s.is_unbounded());
+        assert_eq!(s.scheduled_count(), 1);
+        assert_eq!(s.poll().len(), 2);
+        assert!(s.is_exhausted());
+        assert_eq!(s.scheduled_count(), 0);
+
+        let s = script_with_messages(
+            vec![message(Timestamp::Recurring {
+                next: t - Duration::seconds(2),
+                interval: Duration::seconds(1),
+                remaining: None,
+            })],
+            t,
+        );
+        assert!(!s.is_exhausted());
+        assert!(s.is_unbounded());
+    }
+
+    #[test]
+    fn json_field_topic() {
+        let payload = Payload::from(r#"{"device": {"id": "kitchen", "channel": 2}, "on": true}"#);
+
+        assert_eq!(
+            expand_json_fields("devices/{{json:device.id}}/state", &payload),
+            "devices/kitchen/state"
+        );
+        assert_eq!(
+            expand_json_fields(
+                "devices/{{json:device.id}}/{{json:device.channel}}",
+                &payload
+            ),
+            "devices/kitchen/2"
+        );
+        assert_eq!(expand_json_fields("on/{{json:on}}", &payload), "on/true");
+
+        // Missing or non-scalar fields and non JSON payloads leave the placeholder
+        assert_eq!(
+            expand_json_fields("devices/{{json:device.name}}", &payload),
+            "devices/{{json:device.name}}"
+        );
+        assert_eq!(
+            expand_json_fields("devices/{{json:device}}", &payload),
+            "devices/{{json:device}}"
+        );
+        assert_eq!(
+            expand_json_fields("devices/{{json:device.id}}", &Payload::from("on")),
+            "devices/{{json:device.id}}"
+        );
+
+        assert_eq!(
+            expand_json_fields("devices/kitchen", &payload),
+            "devices/kitchen"
+        );
+    }
+
+    #[test]
+    fn file_payload_resolved_lazily() {
+        let dir = test_dir();
+        let script = dir.path().join("a.txt");
+        fs::write(&script, "0 | test_topic | file:payloads/state.json").unwrap();
+        fs::create_dir(dir.path().join("payloads")).unwrap();
+        let payload = dir.path().join("payloads/state.json");
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        let m = s.messages().next().unwrap().clone();
+        assert_eq!(m.payload_file, Some(payload.clone()));
+
+        // The file only needs to exist when the message is sent, and is read each time
+        std::env::set_var("MQTT_ACTOR_TEST_FILE_PAYLOAD", "kitchen");
+        fs::write(&payload, r#"{"room": "${MQTT_ACTOR_TEST_FILE_PAYLOAD}"}"#).unwrap();
+        let resolved = m.clone().resolve_payload(false).unwrap();
+        assert_eq!(resolved.message, r#"{"room": "kitchen"}"#);
+        assert_eq!(resolved.payload_file, None);
+
+        fs::write(&payload, "off").unwrap();
+        assert_eq!(m.clone().resolve_payload(false).unwrap().message, "off");
+
+        fs::remove_file(&payload).unwrap();
+        assert!(m.resolve_payload(false).is_err());
+    }
+
+    #[test]
+    fn field_whitespace() {
+        let strip = |s: &str| {
+            String::from_utf8(strip_field_whitespace(s.as_bytes(), b'|', Some(b'"'))).unwrap()
+        };
+
+        assert_eq!(strip("  1 | a/b |  msg 1  \n2|c|d"), "1|a/b|msg 1\n2|c|d");
+        assert_eq!(strip(r#"1 | a |  "  x | y  "  "#), r#"1|a|"  x | y  ""#);
+        assert_eq!(
+            strip(r#"1 | a | "say ""hi"" | " "#),
+            r#"1|a|"say ""hi"" | ""#
+        );
+        // Quotes that do not start a field are not special
+        assert_eq!(strip(r#"1 | a | {"x": 1} "#), r#"1|a|{"x": 1}"#);
+    }
+
+    #[test]
+    fn default_topic() {
+        let data = r##"
+1 | msg 1
+2 | test_topic | msg 2
+3 | other_topic | msg 3 | true
+4 | ""
+"##;
+
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        let options = ScriptOptions {
+            default_topic: Some("default/topic".to_string()),
+            ..Default::default()
+        };
+        let msgs = load_messages(&options, Path::new("test.txt"), t, Cursor::new(data)).0;
+        assert_eq!(
+            msgs.iter()
+                .map(|m| (m.topic.as_str(), m.message.to_string_lossy(), m.retain))
+                .collect::<Vec<_>>(),
+            vec![
+                ("default/topic", "msg 1".to_string(), false),
+                ("test_topic", "msg 2".to_string(), false),
+                ("other_topic", "msg 3".to_string(), true),
+                ("default/topic", "".to_string(), false),
+            ]
+        );
+
+        // Without a default topic, rows without a topic are errors
+        let records = read_records(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            Cursor::new(data),
+        );
+        let error = records[0].as_ref().unwrap_err().to_string();
+        assert!(error.contains("test.txt:"), "{}", error);
+        assert!(error.contains("no default topic"), "{}", error);
+        assert!(records[1].is_ok());
+        let (msgs, errors) = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            t,
+            Cursor::new(data),
+        );
+        assert_eq!(
+            msgs.iter().map(|m| m.topic.as_str()).collect::<Vec<_>>(),
+            vec!["test_topic", "other_topic"]
+        );
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn quoted_payloads() {
+        let data = r##"
+1 | test_topic | "a | b"
+2 | test_topic | "  padded  "
+3 | test_topic | "say ""hi"""
+4 | test_topic | {"key": "value"}
+"##;
 
-        // t = 4010
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        assert_eq!(s.poll(), vec![s.messages[3].clone()]);
+        let t = utc(2022, 3, 28, 0, 0, 0);
+        let payloads = |options: &ScriptOptions, data: &str| {
+            load_messages(options, Path::new("test.txt"), t, Cursor::new(data))
+                .0
+                .into_iter()
+                .map(|m| m.message.to_string_lossy())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            payloads(&ScriptOptions::default(), data),
+            vec!["a | b", "  padded  ", r#"say "hi""#, r#"{"key": "value"}"#]
+        );
+
+        let options = ScriptOptions {
+            trim_quoted: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            payloads(&options, data),
+            vec!["a | b", "padded", r#"say "hi""#, r#"{"key": "value"}"#]
+        );
+
+        let options = ScriptOptions {
+            quote: Some(b'\''),
+            ..Default::default()
+        };
+        assert_eq!(
+            payloads(&options, r#"1 | test_topic | 'a | "b"'"#),
+            vec![r#"a | "b""#]
+        );
+
+        // Without quoting the delimiter always separates fields, so the first message has an
+        // invalid retain flag
+        let options = ScriptOptions {
+            quote: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            payloads(&options, data),
+            vec![r#""  padded  ""#, r#""say ""hi""""#, r#"{"key": "value"}"#]
+        );
+    }
+
+    #[test]
+    fn topic_alias_parse() {
+        assert_eq!(
+            parse_topic_alias("home=homeassistant/living_room").unwrap(),
+            ("home".to_string(), "homeassistant/living_room".to_string())
+        );
+        assert!(parse_topic_alias("home").is_err());
+        assert!(parse_topic_alias("=homeassistant").is_err());
+        assert!(parse_topic_alias("a/b=homeassistant").is_err());
+    }
+
+    #[test]
+    fn topic_alias_expand() {
+        let aliases =
+            HashMap::from([("home".to_string(), "homeassistant/living_room".to_string())]);
+
+        assert_eq!(
+            expand_topic_alias("@home/light", &aliases),
+            "homeassistant/living_room/light"
+        );
+        assert_eq!(
+            expand_topic_alias("@home", &aliases),
+            "homeassistant/living_room"
+        );
+        assert_eq!(expand_topic_alias("@away/light", &aliases), "@away/light");
+        assert_eq!(expand_topic_alias("home/light", &aliases), "home/light");
+    }
+
+    #[test]
+    fn env_interpolation() {
+        std::env::set_var("MQTT_ACTOR_TEST_ROOM", "kitchen");
+        std::env::remove_var("MQTT_ACTOR_TEST_MISSING");
+
+        assert_eq!(
+            interpolate_env("home/${MQTT_ACTOR_TEST_ROOM}/light", true).unwrap(),
+            "home/kitchen/light"
+        );
+        assert_eq!(
+            interpolate_env("${MQTT_ACTOR_TEST_ROOM}${MQTT_ACTOR_TEST_ROOM}", true).unwrap(),
+            "kitchenkitchen"
+        );
+        assert_eq!(interpolate_env("$HOME ${", true).unwrap(), "$HOME ${");
+
+        assert!(interpolate_env("home/${MQTT_ACTOR_TEST_MISSING}/light", true).is_err());
+        assert_eq!(
+            interpolate_env("home/${MQTT_ACTOR_TEST_MISSING}/light", false).unwrap(),
+            "home//light"
+        );
+    }
+
+    #[test]
+    fn messages_with_env() {
+        std::env::set_var("MQTT_ACTOR_TEST_DEVICE", "light");
+        std::env::remove_var("MQTT_ACTOR_TEST_UNSET");
+
+        let data = r##"
+0 | home/${MQTT_ACTOR_TEST_DEVICE} | ${MQTT_ACTOR_TEST_DEVICE} on
+0 | home/light                     | ${MQTT_ACTOR_TEST_UNSET}
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].topic, "home/light");
+        assert_eq!(msgs[0].message, "light on");
+        assert_eq!(msgs[1].message, "");
+
+        let options = ScriptOptions {
+            strict_env: true,
+            ..Default::default()
+        };
+        let msgs = load_messages(&options, Path::new("test.txt"), now(), Cursor::new(data)).0;
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].message, "light on");
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let data = "0 | test_topic | msg 1\n0 | test_topic | msg 2\nnope | test_topic | msg 3\n";
+        let records = read_records(
+            &ScriptOptions::default(),
+            Path::new("scripts/test.txt"),
+            Cursor::new(data),
+        );
+        assert_eq!(records.len(), 3);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_ok());
+
+        let e = records[2].as_ref().unwrap_err().to_string();
+        assert!(e.starts_with("scripts/test.txt:3: "), "{}", e);
+    }
+
+    #[test]
+    fn messages_with_multiple_topics() {
+        let data = r##"
+0  | a/b, c/d ,e/f | msg 1
+10 | g/h           | msg 2
+"##;
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(
+            msgs.iter().map(|m| m.topic.as_str()).collect::<Vec<_>>(),
+            vec!["a/b", "c/d", "e/f", "g/h"]
+        );
+        assert!(msgs[..3].iter().all(|m| m.message == "msg 1"));
+        assert!(msgs[..3].iter().all(|m| m.timestamp == msgs[0].timestamp));
+
+        // The relative time of the following message is not affected by the number of topics
+        assert_eq!(
+            msgs[3].timestamp.scheduled_time().unwrap()
+                - msgs[0].timestamp.scheduled_time().unwrap(),
+            Duration::seconds(10)
+        );
+
+        let options = ScriptOptions {
+            topic_separator: ';',
+            ..Default::default()
+        };
+        let msgs = load_messages(&options, Path::new("test.txt"), now(), Cursor::new(data)).0;
+        assert_eq!(msgs[0].topic, "a/b, c/d ,e/f");
+    }
+
+    #[test]
+    fn script_skips_non_utf8_file() {
+        let dir = test_dir();
+        fs::write(dir.path().join("a.txt"), "0 | test_topic | msg").unwrap();
+        fs::write(
+            dir.path().join("b.txt"),
+            b"0 | test_topic | \xff\xfe\n0 | test_topic | msg",
+        )
+        .unwrap();
+
+        let s = Script::new(dir.path(), ScriptOptions::default()).unwrap();
+        assert!(s.files.contains_key(&dir.path().join("a.txt")));
+        assert!(!s.files.contains_key(&dir.path().join("b.txt")));
+
+        let e = s.load_file(&dir.path().join("b.txt")).unwrap_err();
+        assert!(e.to_string().contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn message_display() {
+        let mut m = Message::new(
+            Timestamp::Absolute(utc(2022, 3, 28, 10, 23, 33)),
+            "test_topic",
+            "msg",
+        );
+        assert_eq!(
+            m.to_string(),
+            "[2022-03-28T10:23:33+00:00] test_topic = msg"
+        );
+
+        m.retain = true;
+        m.message = Payload(vec![0xff, 0x00]);
+        assert_eq!(
+            m.to_string(),
+            "[2022-03-28T10:23:33+00:00] test_topic = [ff, 00] (retained)"
+        );
+    }
+
+    #[test]
+    fn timestamp_display() {
+        let t = FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2022, 3, 28, 10, 23, 33)
+            .unwrap();
+
+        assert_eq!(
+            Timestamp::Relative(Duration::seconds(10)).to_string(),
+            "+10s"
+        );
+        assert_eq!(
+            Timestamp::Anchored {
+                anchor: "start".into(),
+                offset: Duration::seconds(-5)
+            }
+            .to_string(),
+            "@start-5s"
+        );
+        assert_eq!(
+            Timestamp::Every {
+                interval: Duration::minutes(1),
+                count: Some(3)
+            }
+            .to_string(),
+            "every 60s, 3 times"
+        );
+        assert_eq!(
+            Timestamp::Recurring {
+                next: t,
+                interval: Duration::seconds(30),
+                remaining: None
+            }
+            .to_string(),
+            "2022-03-28T10:23:33+01:00 (every 30s)"
+        );
+        assert_eq!(
+            Timestamp::Daily(Duration::hours(8)).to_string(),
+            "daily at midnight+28800s"
+        );
+    }
+
+    #[test]
+    fn custom_timestamp_formats() {
+        let formats = vec![
+            "%Y-%m-%d %H:%M:%S%z".to_string(),
+            "%d/%m/%Y %H:%M".to_string(),
+        ];
+
+        // A fixed zone, so that the result does not depend on the timezone of the system
+        let zone = Zone::Named(chrono_tz::Europe::London);
+
+        assert_eq!(
+            parse_with_formats("2022-03-28 10:23:33+0100", &formats, zone),
+            Some(
+                FixedOffset::east_opt(3600)
+                    .unwrap()
+                    .with_ymd_and_hms(2022, 3, 28, 10, 23, 33)
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            parse_with_formats("28/03/2022 10:23", &formats, zone),
+            Some(
+                FixedOffset::east_opt(3600)
+                    .unwrap()
+                    .with_ymd_and_hms(2022, 3, 28, 10, 23, 0)
+                    .unwrap()
+            )
+        );
+        assert_eq!(parse_with_formats("25", &formats, zone), None);
+
+        let data = r##"
+2022-03-28 10:23:33+0100 | test_topic | msg 1
+10                       | test_topic | msg 2
+"##;
+        let options = ScriptOptions {
+            timestamp_formats: formats,
+            ..Default::default()
+        };
+        let msgs = load_messages(&options, Path::new("test.txt"), now(), Cursor::new(data)).0;
+        let t = FixedOffset::east_opt(3600)
+            .unwrap()
+            .with_ymd_and_hms(2022, 3, 28, 10, 23, 33)
+            .unwrap();
+        assert_eq!(msgs[0].timestamp, Timestamp::Absolute(t));
+        assert_eq!(
+            msgs[1].timestamp,
+            Timestamp::Absolute(t + Duration::seconds(10))
+        );
+
+        // Without the custom format the timestamp is not recognised
+        let msgs = load_messages(
+            &ScriptOptions::default(),
+            Path::new("test.txt"),
+            now(),
+            Cursor::new(data),
+        )
+        .0;
+        assert_eq!(msgs.len(), 1);
+    }
+
+    #[test]
+    fn script_summary() {
+        let t = now();
+
+        let message = |timestamp| Message::new(timestamp, "test_topic", "msg");
 
-        // t = 6010
-        std::thread::sleep(std::time::Duration::from_secs(2));
-        assert_eq!(s.poll(), vec![s.messages[4].clone()]);
+        let s = script_with_messages(vec![], t);
+        assert_eq!(
+            s.summary(),
+            Summary {
+                count: 0,
+                earliest: None,
+                latest: None
+            }
+        );
+
+        let s = script_with_messages(
+            vec![
+                message(Timestamp::Absolute(t - Duration::seconds(10))),
+                message(Timestamp::Absolute(t + Duration::seconds(30))),
+                message(Timestamp::Absolute(t + Duration::seconds(10))),
+                message(Timestamp::Recurring {
+                    next: t + Duration::seconds(5),
+                    interval: Duration::seconds(1),
+                    remaining: None,
+                }),
+                message(Timestamp::Daily(Duration::hours(8))),
+            ],
+            t,
+        );
+        assert_eq!(
+            s.summary(),
+            Summary {
+                count: 4,
+                earliest: Some(t + Duration::seconds(5)),
+                latest: Some(t + Duration::seconds(30)),
+            }
+        );
     }
 }
@@ -0,0 +1,54 @@
+use super::{recv_event, Event};
+use anyhow::Result;
+use tokio::{sync::broadcast::Sender, task::JoinHandle};
+
+/// Handles Unix signals other than SIGINT:
+///  - SIGHUP reloads the script
+///  - SIGUSR1 reconnects to the broker(s), reloading credentials
+#[cfg(unix)]
+pub(crate) fn run(tx: Sender<Event>) -> Result<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut rx = tx.subscribe();
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = recv_event(&mut rx, "Signal") => {
+                    if matches!(event, Some(Event::Exit) | None) {
+                        log::debug!("Task exit");
+                        return;
+                    }
+                }
+                _ = sighup.recv() => {
+                    log::info!("Received SIGHUP, reloading script");
+                    if let Err(e) = tx.send(Event::ReloadScript) {
+                        log::error!("Failed to send reload trigger: {}", e);
+                    }
+                }
+                _ = sigusr1.recv() => {
+                    log::info!("Received SIGUSR1, reconnecting");
+                    if let Err(e) = tx.send(Event::Reconnect) {
+                        log::error!("Failed to send reconnect trigger: {}", e);
+                    }
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn run(tx: Sender<Event>) -> Result<JoinHandle<()>> {
+    let mut rx = tx.subscribe();
+
+    Ok(tokio::spawn(async move {
+        while let Some(event) = recv_event(&mut rx, "Signal").await {
+            if event == Event::Exit {
+                log::debug!("Task exit");
+                return;
+            }
+        }
+    }))
+}
@@ -0,0 +1,85 @@
+use super::{metrics::MetricsSnapshot, mqtt::ConnectionState};
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Connection state of a single broker, as reported in the status.
+#[derive(Debug, Serialize)]
+pub(crate) struct BrokerStatus {
+    broker: String,
+    connected: bool,
+    disconnects: u64,
+    reconnect_attempts: u64,
+}
+
+impl BrokerStatus {
+    pub(crate) fn new(broker: &str, state: &Arc<ConnectionState>) -> Self {
+        Self {
+            broker: broker.to_string(),
+            connected: state.is_connected(),
+            disconnects: state.disconnects(),
+            reconnect_attempts: state.reconnect_attempts(),
+        }
+    }
+}
+
+/// Status of the actor, periodically published to the status topic.
+#[derive(Debug, Serialize)]
+pub(crate) struct Status {
+    /// Time since the actor started, in seconds
+    pub uptime: u64,
+    /// Number of script messages sent
+    pub messages_sent: u64,
+    /// Number of script messages still due to be sent
+    pub scheduled: usize,
+    /// Time the script was last reloaded, in RFC3339 format
+    pub last_reload: String,
+    pub brokers: Vec<BrokerStatus>,
+    /// Number of messages published by topic and a histogram of their payload sizes
+    pub metrics: Option<MetricsSnapshot>,
+}
+
+impl Status {
+    pub(crate) fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_to_json() {
+        let state = Arc::new(ConnectionState::default());
+
+        let status = Status {
+            uptime: 120,
+            messages_sent: 5,
+            scheduled: 3,
+            last_reload: "2022-03-28T10:23:33+00:00".to_string(),
+            brokers: vec![BrokerStatus::new("tcp://localhost:1883", &state)],
+            metrics: None,
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&status.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "uptime": 120,
+                "messages_sent": 5,
+                "scheduled": 3,
+                "last_reload": "2022-03-28T10:23:33+00:00",
+                "brokers": [
+                    {
+                        "broker": "tcp://localhost:1883",
+                        "connected": false,
+                        "disconnects": 0,
+                        "reconnect_attempts": 0
+                    }
+                ],
+                "metrics": null
+            })
+        );
+    }
+}
@@ -1,22 +1,137 @@
 use super::Event;
-use std::time::Duration;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{
+    sync::broadcast::{error::TryRecvError, Sender},
+    task::JoinHandle,
+    time::{interval_at, Instant, Interval, MissedTickBehavior},
+};
 
-pub(crate) fn run(tx: Sender<Event>) -> JoinHandle<()> {
+/// Time between ticks, unless they are aligned.
+const TICK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Time from `now` until the next multiple of `granularity` since the Unix epoch.
+/// A time exactly on a boundary waits for the following one.
+fn until_next_boundary(now: SystemTime, granularity: Duration) -> Duration {
+    let since_epoch = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let granularity = granularity.as_nanos();
+    let remaining = granularity - since_epoch % granularity;
+    Duration::from_nanos(remaining as u64)
+}
+
+/// Paces the ticks, either every period or at each boundary of the alignment granularity.
+/// Periodic ticks are scheduled from the first tick rather than from the end of the previous one,
+/// so the time taken between ticks does not make them drift. Ticks that are missed (because the
+/// task was held up for longer than a period) are sent once rather than in a burst.
+enum Ticker {
+    Periodic(Interval),
+    Aligned(Duration),
+}
+
+impl Ticker {
+    fn new(align: Option<Duration>) -> Self {
+        match align {
+            Some(granularity) => Ticker::Aligned(granularity),
+            None => {
+                let mut interval = interval_at(Instant::now() + TICK_PERIOD, TICK_PERIOD);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                Ticker::Periodic(interval)
+            }
+        }
+    }
+
+    /// Waits until the next tick is due.
+    async fn tick(&mut self) {
+        match self {
+            Ticker::Periodic(interval) => {
+                interval.tick().await;
+            }
+            Ticker::Aligned(granularity) => {
+                tokio::time::sleep(until_next_boundary(SystemTime::now(), *granularity)).await;
+            }
+        }
+    }
+}
+
+pub(crate) fn run(tx: Sender<Event>, align: Option<Duration>) -> JoinHandle<()> {
     let mut rx = tx.subscribe();
+    let mut ticker = Ticker::new(align);
 
     tokio::spawn(async move {
         loop {
-            while let Ok(event) = rx.try_recv() {
-                if event == Event::Exit {
-                    log::debug!("Task exit");
-                    return;
+            loop {
+                match rx.try_recv() {
+                    Ok(Event::Exit) => {
+                        log::debug!("Task exit");
+                        return;
+                    }
+                    Err(TryRecvError::Closed) => {
+                        log::warn!("Tick task stopping, the event channel closed without an exit");
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(TryRecvError::Lagged(n)) => {
+                        log::warn!("Tick task fell behind, {} events were dropped", n);
+                    }
+                    Err(TryRecvError::Empty) => break,
                 }
             }
             if let Err(e) = tx.send(Event::Tick) {
                 log::error!("Failed to send tick event: {}", e);
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            ticker.tick().await;
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_alignment() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_648_462_980); // 2022-03-28T10:23:00Z
+
+        let minute = Duration::from_secs(60);
+        assert_eq!(until_next_boundary(t, minute), minute);
+        assert_eq!(
+            until_next_boundary(t + Duration::from_millis(33_250), minute),
+            Duration::from_millis(26_750)
+        );
+
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            until_next_boundary(t + Duration::from_millis(400), second),
+            Duration::from_millis(600)
+        );
+
+        let five_minutes = Duration::from_secs(300);
+        assert_eq!(
+            until_next_boundary(t, five_minutes),
+            Duration::from_secs(120)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_ticks_do_not_drift() {
+        let start = Instant::now();
+        let mut ticker = Ticker::new(None);
+
+        for i in 1..=5 {
+            // Processing between ticks
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            ticker.tick().await;
+            assert_eq!(start.elapsed(), TICK_PERIOD * i);
+        }
+
+        // Processing that takes longer than a period ticks once straight away, then continues on
+        // the original schedule
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        ticker.tick().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(6500));
+        ticker.tick().await;
+        assert_eq!(start.elapsed(), TICK_PERIOD * 7);
+    }
+}
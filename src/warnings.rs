@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Time within which a warning that was already logged is not logged again.
+const SUPPRESSION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Tracks recently logged warnings, so that identical warnings (e.g. for a malformed script file
+/// that is reloaded repeatedly while it is edited) are not repeated.
+struct RecentWarnings {
+    window: Duration,
+    seen: HashMap<String, Instant>,
+}
+
+impl RecentWarnings {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Checks if a warning should be logged at the given time, recording it if so.
+    fn should_log(&mut self, message: &str, now: Instant) -> bool {
+        let window = self.window;
+        self.seen
+            .retain(|_, logged| now.saturating_duration_since(*logged) < window);
+
+        if self.seen.contains_key(message) {
+            false
+        } else {
+            self.seen.insert(message.to_string(), now);
+            true
+        }
+    }
+}
+
+static RECENT_WARNINGS: Mutex<Option<RecentWarnings>> = Mutex::new(None);
+
+/// Logs a warning, unless the same warning was already logged recently.
+pub(crate) fn warn(message: String) {
+    let should_log = match RECENT_WARNINGS.lock() {
+        Ok(mut recent) => recent
+            .get_or_insert_with(|| RecentWarnings::new(SUPPRESSION_WINDOW))
+            .should_log(&message, Instant::now()),
+        Err(_) => true,
+    };
+
+    if should_log {
+        log::warn!("{}", message);
+    } else {
+        log::debug!("Suppressed repeated warning: {}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppress_repeated_warnings() {
+        let mut recent = RecentWarnings::new(Duration::from_secs(10));
+        let t = Instant::now();
+
+        assert!(recent.should_log("warning 1", t));
+        assert!(!recent.should_log("warning 1", t + Duration::from_secs(5)));
+        assert!(recent.should_log("warning 2", t + Duration::from_secs(5)));
+
+        // Logged again once the window has passed since it was last logged
+        assert!(recent.should_log("warning 1", t + Duration::from_secs(10)));
+        assert!(!recent.should_log("warning 2", t + Duration::from_secs(14)));
+        assert!(recent.should_log("warning 2", t + Duration::from_secs(15)));
+        assert!(!recent.should_log("warning 1", t + Duration::from_secs(15)));
+    }
+}